@@ -0,0 +1,84 @@
+//! Writes a synthetic combined format access log for measuring the import
+//!
+//! ```sh
+//! cargo run --release --example gen_log -- 2048 > /tmp/access_log
+//! ```
+//!
+//! The argument is the size in megabytes, 1024 by default. The output is the
+//! same for the same size.
+
+use chrono::DateTime;
+use std::io::{self, BufWriter, Write};
+
+const USERAGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/94.0.4606.81 Safari/537.36",
+    "Mozilla/5.0 (X11; Linux x86_64; rv:93.0) Gecko/20100101 Firefox/93.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.0 Safari/605.1.15",
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 15_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.0 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (Linux; Android 11; SM-G991B) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/94.0.4606.71 Mobile Safari/537.36",
+    "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+    "curl/7.68.0",
+];
+
+const REFERRERS: &[&str] = &[
+    "-",
+    "-",
+    "https://www.google.com/",
+    "https://www.google.com/search?q=loggerson",
+    "https://duckduckgo.com/",
+    "https://t.co/abc123",
+    "https://www.example.com/",
+];
+
+/// Xorshift, enough for picking the fields
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn main() -> io::Result<()> {
+    let megabytes: u64 = std::env::args()
+        .nth(1)
+        .map(|arg| arg.parse().expect("size in megabytes"))
+        .unwrap_or(1024);
+    let mut out = BufWriter::with_capacity(1 << 20, io::stdout().lock());
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    // 2021-10-01T00:00:00Z
+    let mut timestamp = 1_633_046_400;
+    let mut written = 0;
+    while written < megabytes << 20 {
+        timestamp += rng.below(2) as i64;
+        let user = rng.below(50_000);
+        let time = DateTime::from_timestamp(timestamp, 0).unwrap();
+        let url = match rng.below(10) {
+            0 => format!("/static/app.{}.js", rng.below(20)),
+            1 => format!("/search?q=term{}", rng.below(5_000)),
+            _ => format!("/blog/post-{}", rng.below(2_000)),
+        };
+        let line = format!(
+            "10.{}.{}.{} - - [{}] \"GET {} HTTP/1.1\" {} {} \"{}\" \"{}\"\n",
+            user >> 16,
+            (user >> 8) & 0xff,
+            user & 0xff,
+            time.format("%d/%b/%Y:%H:%M:%S %z"),
+            url,
+            [200, 200, 200, 200, 304, 404][rng.below(6)],
+            rng.below(100_000),
+            REFERRERS[rng.below(REFERRERS.len())],
+            USERAGENTS[user % USERAGENTS.len()],
+        );
+        out.write_all(line.as_bytes())?;
+        written += line.len() as u64;
+    }
+    out.flush()
+}
@@ -0,0 +1,350 @@
+//! Input read in large blocks of whole lines, which the parsed entries borrow
+//! from until they are inserted
+
+use memchr::{memchr, memchr_iter, memrchr};
+use memmap2::Mmap;
+use std::io::{self, Read};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// Whole lines, the last one possibly without a line end
+#[derive(Debug)]
+pub struct Block {
+    pub bytes: Bytes,
+    /// Line number of the first line in the input, starting from 1
+    pub first_line: usize,
+}
+
+/// Bytes of a block, read to a buffer or a range of a memory mapped file
+#[derive(Debug)]
+pub enum Bytes {
+    Read(Vec<u8>),
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytes::Read(bytes) => bytes,
+            Bytes::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+/// Lines of the block without the `\n`
+pub fn lines(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::with_capacity(bytes.len() / 128);
+    let mut start = 0;
+    for end in memchr_iter(b'\n', bytes) {
+        lines.push(&bytes[start..end]);
+        start = end + 1;
+    }
+    if start < bytes.len() {
+        lines.push(&bytes[start..]);
+    }
+    lines
+}
+
+/// Splits the bytes to at most `parts` ranges of about equal size, each cut
+/// after a line end, so that the lines can be split in parallel
+pub fn split(bytes: &[u8], parts: usize) -> Vec<&[u8]> {
+    let size = bytes.len() / parts.max(1) + 1;
+    let mut ranges = Vec::with_capacity(parts);
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let end = match rest.get(size..).and_then(|tail| memchr(b'\n', tail)) {
+            Some(line_end) => size + line_end + 1,
+            None => rest.len(),
+        };
+        let (range, tail) = rest.split_at(end);
+        ranges.push(range);
+        rest = tail;
+    }
+    ranges
+}
+
+/// Reads blocks of at least `block_bytes`, cut after the last line end. The
+/// rest is carried over to the next block.
+pub struct BlockReader<R> {
+    reader: R,
+    block_bytes: usize,
+    carry: Vec<u8>,
+    next_line: usize,
+    /// Returned after the block read before it
+    error: Option<io::Error>,
+    done: bool,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(reader: R, block_bytes: usize) -> Self {
+        BlockReader {
+            reader,
+            block_bytes,
+            carry: Vec::new(),
+            next_line: 1,
+            error: None,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for BlockReader<R> {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        if self.done {
+            return None;
+        }
+        let mut bytes = std::mem::take(&mut self.carry);
+        bytes.reserve(self.block_bytes);
+
+        // Until the block is full and has a line end, or the input ends
+        let end = loop {
+            let wanted = self.block_bytes.saturating_sub(bytes.len()).max(64 * 1024);
+            match (&mut self.reader)
+                .take(wanted as u64)
+                .read_to_end(&mut bytes)
+            {
+                Ok(0) => {
+                    self.done = true;
+                    break bytes.len();
+                }
+                Ok(_) if bytes.len() >= self.block_bytes => {
+                    if let Some(last_line_end) = memrchr(b'\n', &bytes) {
+                        break last_line_end + 1;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.done = true;
+                    if bytes.is_empty() {
+                        return Some(Err(err));
+                    }
+                    self.error = Some(err);
+                    break bytes.len();
+                }
+            }
+        };
+        self.carry = bytes.split_off(end);
+        if bytes.is_empty() {
+            return None;
+        }
+
+        let first_line = self.next_line;
+        self.next_line += memchr_iter(b'\n', &bytes).count();
+        Some(Ok(Block {
+            bytes: Bytes::Read(bytes),
+            first_line,
+        }))
+    }
+}
+
+/// Cuts a memory mapped file to blocks of at least `block_bytes`, after the
+/// first line end, without copying
+pub struct MappedBlocks {
+    map: Arc<Mmap>,
+    block_bytes: usize,
+    start: usize,
+    next_line: usize,
+}
+
+impl MappedBlocks {
+    pub fn new(map: Mmap, block_bytes: usize) -> Self {
+        MappedBlocks {
+            map: Arc::new(map),
+            block_bytes,
+            start: 0,
+            next_line: 1,
+        }
+    }
+}
+
+impl Iterator for MappedBlocks {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.map[self.start..];
+        if rest.is_empty() {
+            return None;
+        }
+        let len = match rest
+            .get(self.block_bytes..)
+            .and_then(|tail| memchr(b'\n', tail))
+        {
+            Some(line_end) => self.block_bytes + line_end + 1,
+            None => rest.len(),
+        };
+        let first_line = self.next_line;
+        self.next_line += memchr_iter(b'\n', &rest[..len]).count();
+        let range = self.start..self.start + len;
+        self.start += len;
+        Some(Ok(Block {
+            bytes: Bytes::Mapped(self.map.clone(), range),
+            first_line,
+        }))
+    }
+}
+
+/// Joins lines to blocks of at least `block_bytes`, for input which is
+/// already split to lines
+pub struct LineBlocks<I> {
+    lines: I,
+    block_bytes: usize,
+    next_line: usize,
+    /// Returned after the block read before it
+    error: Option<io::Error>,
+}
+
+impl<I> LineBlocks<I> {
+    pub fn new(lines: I, block_bytes: usize) -> Self {
+        LineBlocks {
+            lines,
+            block_bytes,
+            next_line: 1,
+            error: None,
+        }
+    }
+}
+
+impl<I, L> Iterator for LineBlocks<I>
+where
+    I: Iterator<Item = io::Result<L>>,
+    L: AsRef<[u8]>,
+{
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        let first_line = self.next_line;
+        let mut bytes = Vec::new();
+        while bytes.len() < self.block_bytes {
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    bytes.extend_from_slice(line.as_ref());
+                    bytes.push(b'\n');
+                    self.next_line += 1;
+                }
+                // Numbering continues after the failed line
+                Some(Err(err)) => {
+                    self.next_line += 1;
+                    if bytes.is_empty() {
+                        return Some(Err(err));
+                    }
+                    self.error = Some(err);
+                    break;
+                }
+                None => break,
+            }
+        }
+        (!bytes.is_empty()).then_some(Ok(Block {
+            bytes: Bytes::Read(bytes),
+            first_line,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lines, split, BlockReader, LineBlocks, MappedBlocks};
+    use memmap2::Mmap;
+    use std::io::{self, Write};
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(vec![b"a" as &[u8], b"", b"bc"], lines(b"a\n\nbc\n"));
+        assert_eq!(vec![b"a" as &[u8], b"bc"], lines(b"a\nbc"));
+        assert!(lines(b"").is_empty());
+    }
+
+    #[test]
+    fn test_split() {
+        let bytes = b"aaaa\nbb\ncccccc\nd\n";
+        assert_eq!(
+            vec![b"aaaa\nbb\n" as &[u8], b"cccccc\n", b"d\n"],
+            split(bytes, 4)
+        );
+        assert_eq!(vec![bytes as &[u8]], split(bytes, 1));
+        assert_eq!(bytes.len(), split(bytes, 100).concat().len());
+        assert!(split(b"", 4).is_empty());
+    }
+
+    #[test]
+    fn test_block_reader() {
+        let input = b"first line\nsecond\nthird line\n4\nlast".to_vec();
+        let blocks = BlockReader::new(&input[..], 8)
+            .map(|block| block.unwrap())
+            .map(|block| {
+                (
+                    block.first_line,
+                    String::from_utf8(block.bytes.to_vec()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (1, "first line\nsecond\nthird line\n4\n".to_owned()),
+                (5, "last".to_owned())
+            ],
+            blocks
+        );
+
+        // Lines longer than the block
+        let input = "x".repeat(100_000) + "\n" + &"y".repeat(100_000) + "\nz\n";
+        let blocks = BlockReader::new(input.as_bytes(), 70_000)
+            .map(|block| block.unwrap())
+            .map(|block| (block.first_line, block.bytes.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(1, 100_001), (2, 100_003)], blocks);
+    }
+
+    #[test]
+    fn test_mapped_blocks() {
+        let path = std::env::temp_dir().join("loggerson_test_mapped_blocks.log");
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"first line\nsecond\nthird line\n4\nlast")
+            .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let map = unsafe { Mmap::map(&file) }.unwrap();
+        let blocks = MappedBlocks::new(map, 8)
+            .map(|block| block.unwrap())
+            .map(|block| {
+                (
+                    block.first_line,
+                    String::from_utf8(block.bytes.to_vec()).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (1, "first line\n".to_owned()),
+                (2, "second\nthird line\n".to_owned()),
+                (4, "4\nlast".to_owned())
+            ],
+            blocks
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_line_blocks() {
+        let lines = vec![
+            Ok("abc".to_owned()),
+            Ok("def".to_owned()),
+            Err(io::Error::other("failed")),
+            Ok("ghi".to_owned()),
+        ];
+        let blocks = LineBlocks::new(lines.into_iter(), 5).collect::<Vec<_>>();
+        assert_eq!(3, blocks.len());
+        assert_eq!(b"abc\ndef\n", &blocks[0].as_ref().unwrap().bytes[..]);
+        assert!(blocks[1].is_err());
+        assert_eq!(4, blocks[2].as_ref().unwrap().first_line);
+        assert_eq!(b"ghi\n", &blocks[2].as_ref().unwrap().bytes[..]);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::models::{LogEntry, User};
+use crate::useragent::classify;
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What to do with the rows of users detected as bots
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BotMode {
+    /// Import bot rows as is
+    Keep,
+    /// Import bot rows and set `users.is_bot`
+    Mark,
+    /// Skip bot rows. Users detected after some of their rows are imported,
+    /// e.g. in a later chunk or by requesting nothing but `/robots.txt`, are
+    /// marked instead.
+    Drop,
+}
+
+/// Useragent words of known bots and tools, matched case insensitively at
+/// word boundaries, so that `bot` doesn't match e.g. `CUBOT` phones. Bots
+/// named like `Googlebot` are found by the useragent classification.
+static KNOWN_BOT_USERAGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "libwww-perl",
+    "httpclient",
+    "headlesschrome",
+    "masscan",
+    "zgrab",
+    "nmap",
+    "nikto",
+    "sqlmap",
+];
+
+/// Paths requested mostly by vulnerability scanners, query string is ignored
+static SCANNER_PATHS: &[&str] = &[
+    "/wp-login.php",
+    "/xmlrpc.php",
+    "/.env",
+    "/.git/config",
+    "/phpmyadmin/",
+    "/wp-admin/setup-config.php",
+];
+
+/// Bot rules, and the users found by them during one import
+pub struct BotFilter {
+    useragents: Vec<String>,
+    paths: Vec<String>,
+    useragent_cache: HashMap<String, bool>,
+    /// Users detected by the rules in the chunks so far
+    bots: HashSet<User<'static>>,
+    /// Users which have requested nothing but `/robots.txt` so far, with the
+    /// number of the requests
+    robots_txt: HashMap<User<'static>, usize>,
+    /// Users which have requested something else
+    browsing: HashSet<User<'static>>,
+    /// Users detected with rows in the previous chunks, see `take_late_bots`
+    late: Vec<User<'static>>,
+}
+
+impl Default for BotFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BotFilter {
+    pub fn new() -> Self {
+        BotFilter {
+            useragents: KNOWN_BOT_USERAGENTS.iter().map(|s| s.to_string()).collect(),
+            paths: SCANNER_PATHS.iter().map(|s| s.to_string()).collect(),
+            useragent_cache: HashMap::new(),
+            bots: HashSet::new(),
+            robots_txt: HashMap::new(),
+            browsing: HashSet::new(),
+            late: Vec::new(),
+        }
+    }
+
+    /// Reads additional rules from a file, one per line. Lines starting with
+    /// `/` are scanner paths, other lines are useragent words.
+    pub fn extend_from_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('/') {
+                self.paths.push(line.to_owned());
+            } else {
+                self.useragents.push(line.to_lowercase());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_bot_useragent(&mut self, useragent: &str) -> bool {
+        if let Some(is_bot) = self.useragent_cache.get(useragent) {
+            return *is_bot;
+        }
+        let lowercase = useragent.to_lowercase();
+        let is_bot = self
+            .useragents
+            .iter()
+            .any(|ua| contains_word(&lowercase, ua))
+            || classify(useragent).is_bot;
+        self.useragent_cache.insert(useragent.to_owned(), is_bot);
+        is_bot
+    }
+
+    fn is_scanner_path(&self, url: &str) -> bool {
+        let path = url.split('?').next().unwrap_or(url);
+        self.paths.iter().any(|p| path == p)
+    }
+
+    /// Finds the users in the chunk which look like bots by a known bot
+    /// useragent or requests to scanner paths, in this or the previous chunks
+    /// of the import. Users requesting nothing but `/robots.txt` are known
+    /// only at the end, see `take_robots_txt_only`.
+    pub fn find_bots<'a>(&mut self, entries: &[LogEntry<'a>]) -> HashSet<User<'a>> {
+        let mut bots = HashSet::new();
+        let mut first_seen = HashSet::new();
+        for entry in entries {
+            let user = &entry.user;
+            if !self.browsing.contains(user) && !self.robots_txt.contains_key(user) {
+                first_seen.insert(user);
+            }
+            if entry.request.url == "/robots.txt" {
+                if !self.browsing.contains(user) {
+                    *self
+                        .robots_txt
+                        .entry(user.clone().into_owned())
+                        .or_default() += 1;
+                }
+            } else if !self.browsing.contains(user) {
+                let user = user.clone().into_owned();
+                self.robots_txt.remove(&user);
+                self.browsing.insert(user);
+            }
+
+            if bots.contains(user) {
+                continue;
+            }
+            let is_bot = self.bots.contains(user)
+                || self.is_scanner_path(&entry.request.url)
+                || user
+                    .useragent
+                    .as_ref()
+                    .is_some_and(|ua| self.is_bot_useragent(&ua.value));
+            if is_bot {
+                if !self.bots.contains(user) {
+                    self.bots.insert(user.clone().into_owned());
+                    if !first_seen.contains(user) {
+                        self.late.push(user.clone().into_owned());
+                    }
+                }
+                bots.insert(user.clone());
+            }
+        }
+        bots
+    }
+
+    /// Users detected since the last call who had rows in the chunks before
+    /// the one they were detected in
+    pub fn take_late_bots(&mut self) -> Vec<User<'static>> {
+        std::mem::take(&mut self.late)
+    }
+
+    /// Users which requested nothing but `/robots.txt` during the import, with
+    /// the number of the requests. Called once after all the chunks.
+    pub fn take_robots_txt_only(&mut self) -> HashMap<User<'static>, usize> {
+        let bots = &self.bots;
+        self.robots_txt
+            .drain()
+            .filter(|(user, _)| !bots.contains(user))
+            .collect()
+    }
+}
+
+/// Whether `word` is in `text`, not as a part of a longer word. Rules which
+/// start or end with punctuation, e.g. `curl/`, aren't bounded at that end.
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_word_char = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    let bounded_start = is_word_char(word.chars().next());
+    let bounded_end = is_word_char(word.chars().next_back());
+    text.match_indices(word).any(|(start, _)| {
+        let end = start + word.len();
+        let joined_before = bounded_start && is_word_char(text[..start].chars().next_back());
+        let joined_after = bounded_end && is_word_char(text[end..].chars().next());
+        !joined_before && !joined_after
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contains_word, BotFilter};
+    use crate::models::*;
+
+    fn entry(hash: i64, url: &str, useragent: &str) -> LogEntry<'static> {
+        let mut entry = LogEntry::test(100, hash, url);
+        entry.user.useragent = Some(Useragent {
+            value: useragent.to_owned().into(),
+        });
+        entry
+    }
+
+    #[test]
+    fn test_find_bots() {
+        let browser =
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:95.0) Gecko/20100101 Firefox/95.0";
+        let entries = vec![
+            entry(1, "/", browser),
+            entry(1, "/robots.txt", browser),
+            entry(2, "/robots.txt", browser),
+            entry(3, "/wp-login.php?foo", browser),
+            entry(4, "/", "curl/7.68.0"),
+            entry(5, "/", "Mozilla/5.0 (compatible; bingbot/2.0)"),
+        ];
+        let mut filter = BotFilter::new();
+        let mut bots = filter
+            .find_bots(&entries)
+            .into_iter()
+            .map(|u| u.hash.unwrap())
+            .collect::<Vec<_>>();
+        bots.sort();
+        assert_eq!(vec![3, 4, 5], bots);
+
+        assert!(filter.take_late_bots().is_empty());
+
+        // Robots.txt only is decided after all the chunks, user 1 requested
+        // something else before
+        let entries = vec![entry(2, "/robots.txt", browser), entry(6, "/", browser)];
+        assert!(filter.find_bots(&entries).is_empty());
+        // User 1 is detected after the rows of the first chunk
+        let entries = vec![
+            entry(6, "/robots.txt", browser),
+            entry(3, "/", browser),
+            entry(1, "/.env", browser),
+        ];
+        let mut bots = filter
+            .find_bots(&entries)
+            .into_iter()
+            .map(|u| u.hash.unwrap())
+            .collect::<Vec<_>>();
+        bots.sort();
+        assert_eq!(vec![1, 3], bots);
+        let late = filter.take_late_bots();
+        assert_eq!(
+            vec![Some(1)],
+            late.into_iter().map(|u| u.hash).collect::<Vec<_>>()
+        );
+        let robots_txt_only = filter
+            .take_robots_txt_only()
+            .into_iter()
+            .map(|(u, count)| (u.hash.unwrap(), count))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(2, 2)], robots_txt_only);
+    }
+
+    #[test]
+    fn test_bot_useragent_words() {
+        assert!(contains_word("foo bot/1.0", "bot"));
+        assert!(contains_word("curl/7.68.0", "curl/"));
+        assert!(!contains_word(
+            "mozilla/5.0 (linux; android 10; cubot x30)",
+            "bot"
+        ));
+        assert!(!contains_word("notcurl/7.68.0", "curl/"));
+
+        let mut filter = BotFilter::new();
+        let phone = "Mozilla/5.0 (Linux; Android 10; CUBOT X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0 Mobile Safari/537.36";
+        assert!(!filter.is_bot_useragent(phone));
+        assert!(filter.is_bot_useragent("Mozilla/5.0 (compatible; Googlebot/2.1)"));
+    }
+}
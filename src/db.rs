@@ -1,19 +1,72 @@
 use crate::{
-    models::{LogEntry, Referrer, Request, User, Useragent},
+    importer::{Dedup, Msg},
+    models::{Country, LogEntry, Referrer, Request, User, Useragent},
+    referrers::{classify_host, parse_referrer},
+    rollups::{add_repeat_hits, last_entry_id, update_rollups},
+    sketches::{backfill_sketches, compact_sketches, update_sketches},
+    useragent::classify,
     utils::{ExtendTo, SendErrorsAsExt, SendErrorsExt},
-    Msg,
 };
 use derive_more::From;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Connection, ErrorCode};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags};
 use std::collections::HashMap;
 
 const SCHEMA: &str = include_str!("schema.sql");
 
+/// Changes to the tables of databases created by earlier versions, which
+/// `CREATE TABLE IF NOT EXISTS` in the schema leaves as they are. `PRAGMA
+/// user_version` is the number applied, a new database starts with all of
+/// them applied.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    // Country looked up with `--geoip`, the index is recreated by the schema
+    |con| {
+        add_column(
+            con,
+            "users",
+            "country_id",
+            "INTEGER REFERENCES countries(id)",
+        )?;
+        con.execute_batch("DROP INDEX IF EXISTS users_cols")?;
+        Ok(())
+    },
+    // Users detected as bots
+    |con| add_column(con, "users", "is_bot", "BOOLEAN NOT NULL DEFAULT 0"),
+    // Session of the entry, the existing entries are sessioned by the next
+    // import. The partial index is created by the schema.
+    |con| {
+        add_column(
+            con,
+            "entrys",
+            "session_id",
+            "INTEGER REFERENCES sessions(id)",
+        )
+    },
+    // Parts of the referrer url, NULL for the existing ones
+    |con| {
+        add_column(con, "referrers", "scheme", "TEXT")?;
+        add_column(
+            con,
+            "referrers",
+            "domain_id",
+            "INTEGER REFERENCES referrer_domains(id)",
+        )?;
+        add_column(con, "referrers", "path", "TEXT")
+    },
+    // Time fraction, logged offset, line number, source and hit count. All
+    // but the offset and count are part of the unique key, which SQLite
+    // can't alter.
+    rebuild_entrys,
+    // Sketches of a few users stored sparse, the tables may be missing
+    compact_sketches,
+];
+
 #[derive(From, Debug)]
 pub enum DbError {
     SqliteError(rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    PostgresError(postgres::Error),
     DuplicateEntry,
 }
 
@@ -23,18 +76,149 @@ pub fn init(path: &str) -> Result<Pool<SqliteConnectionManager>> {
     // let manager = SqliteConnectionManager::memory();
     let manager = SqliteConnectionManager::file(path);
     let pool = r2d2::Pool::new(manager).unwrap();
-    let conn = pool.get().unwrap();
-    let _ = conn
-        .query_row("PRAGMA journal_mode = WAL", [], |_row| Ok(()))
+    let mut conn = pool.get().unwrap();
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_row| Ok(()))
         .unwrap();
+    let rollups = table_exists(&conn, "rollup_requests")?;
+    let sketches = table_exists(&conn, "sketches_daily")?;
+    let referrer_parts = column_exists(&conn, "referrers", "domain_id")?;
+    migrate(&mut conn)?;
     conn.execute_batch(SCHEMA)?;
+
+    // The useragent details, referrer parts, rollups and sketches of a
+    // database from an earlier version are built once from its rows.
+    // Useragents first, as the browser rollups are read from them.
+    let tx = conn.transaction()?;
+    classify_useragents(&tx)?;
+    if !referrer_parts {
+        parse_referrers(&tx)?;
+    }
+    if !rollups {
+        update_rollups(&tx, 0)?;
+    }
+    if !sketches {
+        backfill_sketches(&tx)?;
+    }
+    tx.commit()?;
+    Ok(pool)
+}
+
+pub(crate) fn table_exists(con: &Connection, name: &str) -> Result<bool> {
+    Ok(con.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        [name],
+        |row| row.get(0),
+    )?)
+}
+
+/// Applies the migrations missing from an existing database, each in its own
+/// transaction
+fn migrate(con: &mut Connection) -> Result<()> {
+    let version: i64 = con.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if !table_exists(con, "entrys")? {
+        con.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+        return Ok(());
+    }
+    // Tables may refer to ones the schema creates only after the migrations,
+    // such as `sessions`, and copying rows would check those references
+    con.pragma_update(None, "foreign_keys", false)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = con.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index as i64 + 1)?;
+        tx.commit()?;
+    }
+    con.pragma_update(None, "foreign_keys", true)?;
+    Ok(())
+}
+
+/// Copies the entries to a table with the current columns and unique key,
+/// keeping the columns of earlier versions. The schema recreates the indexes.
+fn rebuild_entrys(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+        CREATE TABLE entrys_new (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          timestamp       BIGINT          NOT NULL,
+          micros          INTEGER         NOT NULL DEFAULT 0,
+          utc_offset      INTEGER         NOT NULL DEFAULT 0,
+          line_number     BIGINT          NOT NULL DEFAULT 0,
+          source          INTEGER         NOT NULL DEFAULT 0,
+          hit_count       INTEGER         NOT NULL DEFAULT 1,
+          request_id      INTEGER         NOT NULL,
+          user_id         INTEGER         NOT NULL,
+          referrer_id     INTEGER,
+          session_id      INTEGER,
+          FOREIGN KEY (request_id) REFERENCES requests(id),
+          FOREIGN KEY (user_id) REFERENCES users(id),
+          FOREIGN KEY (referrer_id) REFERENCES referrers(id),
+          FOREIGN KEY (session_id) REFERENCES sessions(id),
+          UNIQUE (timestamp, micros, source, line_number, request_id, user_id)
+        )
+        ",
+    )?;
+    let columns: Vec<String> = con
+        .prepare(
+            "
+            SELECT name FROM pragma_table_info('entrys')
+            WHERE name IN (SELECT name FROM pragma_table_info('entrys_new'))
+            ",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    con.execute_batch(&format!(
+        "
+        INSERT INTO entrys_new({columns}) SELECT {columns} FROM entrys;
+        DROP TABLE entrys;
+        ALTER TABLE entrys_new RENAME TO entrys;
+        ",
+        columns = columns.join(", ")
+    ))?;
+    Ok(())
+}
+
+/// Adds the column unless the database is from a version which has it
+fn column_exists(con: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(con.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?) WHERE name = ?)",
+        params![table, column],
+        |row| row.get(0),
+    )?)
+}
+
+fn add_column(con: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !column_exists(con, table, column)? {
+        con.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
+}
+
+/// Opens an existing database without writing to it, e.g. the schema. Readers
+/// don't block the WAL writer, so an import can run at the same time.
+pub fn open_read_only(path: &str) -> Result<Pool<SqliteConnectionManager>> {
+    let manager = SqliteConnectionManager::file(path).with_flags(
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    );
+    let pool = r2d2::Pool::new(manager).unwrap();
+    pool.get()
+        .unwrap()
+        .query_row("SELECT 1", [], |_row| Ok(()))?;
     Ok(pool)
 }
+
+#[derive(Default)]
 pub struct BatchCache {
-    pub useragents_cache: HashMap<Useragent, i32>,
-    pub users_cache: HashMap<User, i32>,
-    pub requests_cache: HashMap<Request, i32>,
-    pub referrer_cache: HashMap<Referrer, i32>,
+    pub useragents_cache: HashMap<Useragent<'static>, i32>,
+    pub users_cache: HashMap<User<'static>, i32>,
+    pub requests_cache: HashMap<Request<'static>, i32>,
+    pub referrer_cache: HashMap<Referrer<'static>, i32>,
+    pub countries_cache: HashMap<Country, i32>,
+    pub referrer_domains_cache: HashMap<String, i32>,
 }
 
 impl BatchCache {
@@ -44,6 +228,8 @@ impl BatchCache {
             users_cache: HashMap::new(),
             requests_cache: HashMap::new(),
             referrer_cache: HashMap::new(),
+            countries_cache: HashMap::new(),
+            referrer_domains_cache: HashMap::new(),
         }
     }
 
@@ -64,14 +250,14 @@ impl BatchCache {
                 .mapped(|row| {
                     Ok((
                         Request {
-                            method: row.get(1)?,
-                            url: row.get(2)?,
+                            method: row.get::<_, String>(1)?.into(),
+                            url: row.get::<_, String>(2)?.into(),
                             status_code: row.get(3)?,
                         },
                         row.get(0)?,
                     ))
                 })
-                .send_errors_as(&error_channel, DbError::SqliteError)
+                .send_errors_as(error_channel, DbError::SqliteError)
                 .extend_to(&mut self.requests_cache);
         }
 
@@ -83,24 +269,30 @@ impl BatchCache {
                     DISTINCT 
                     u.id as user_id, 
                     u.hash as user_hash, 
-                    ua.value as useragent_value
-                FROM users u LEFT JOIN useragents ua 
-                ON u.useragent_id = ua.id
+                    ua.value as useragent_value,
+                    c.code as country_code
+                FROM users u 
+                LEFT JOIN useragents ua ON u.useragent_id = ua.id
+                LEFT JOIN countries c ON u.country_id = c.id
                 ",
             )?;
 
             stmt.query([])?
                 .mapped(|row| {
                     let ua: Option<String> = row.get(2)?;
+                    let country: Option<String> = row.get(3)?;
                     Ok((
                         User {
                             hash: row.get(1)?,
-                            useragent: ua.map(|value| Useragent { value }),
+                            useragent: ua.map(|value| Useragent {
+                                value: value.into(),
+                            }),
+                            country: country.map(|code| Country { code }),
                         },
                         row.get(0)?,
                     ))
                 })
-                .send_errors_as(&error_channel, DbError::SqliteError)
+                .send_errors_as(error_channel, DbError::SqliteError)
                 .extend_to(&mut self.users_cache);
         }
 
@@ -116,8 +308,16 @@ impl BatchCache {
             )?;
 
             stmt.query([])?
-                .mapped(|row| Ok((Useragent { value: row.get(1)? }, row.get(0)?)))
-                .send_errors_as(&error_channel, DbError::SqliteError)
+                .mapped(|row| {
+                    let value: String = row.get(1)?;
+                    Ok((
+                        Useragent {
+                            value: value.into(),
+                        },
+                        row.get(0)?,
+                    ))
+                })
+                .send_errors_as(error_channel, DbError::SqliteError)
                 .extend_to(&mut self.useragents_cache);
         }
 
@@ -133,10 +333,47 @@ impl BatchCache {
             )?;
 
             stmt.query([])?
-                .mapped(|row| Ok((Referrer { url: row.get(1)? }, row.get(0)?)))
-                .send_errors_as(&error_channel, DbError::SqliteError)
+                .mapped(|row| {
+                    let url: String = row.get(1)?;
+                    Ok((Referrer { url: url.into() }, row.get(0)?))
+                })
+                .send_errors_as(error_channel, DbError::SqliteError)
                 .extend_to(&mut self.referrer_cache);
         }
+
+        {
+            // Update countries cache
+            let mut stmt = con.prepare_cached(
+                "
+                SELECT 
+                    c.id,
+                    c.code
+                FROM countries c
+                ",
+            )?;
+
+            stmt.query([])?
+                .mapped(|row| Ok((Country { code: row.get(1)? }, row.get(0)?)))
+                .send_errors_as(error_channel, DbError::SqliteError)
+                .extend_to(&mut self.countries_cache);
+        }
+
+        {
+            // Update referrer domains cache
+            let mut stmt = con.prepare_cached(
+                "
+                SELECT 
+                    d.id,
+                    d.host
+                FROM referrer_domains d
+                ",
+            )?;
+
+            stmt.query([])?
+                .mapped(|row| Ok((row.get(1)?, row.get(0)?)))
+                .send_errors_as(error_channel, DbError::SqliteError)
+                .extend_to(&mut self.referrer_domains_cache);
+        }
         Ok(())
     }
 }
@@ -156,9 +393,11 @@ fn insert_request(caches: &mut BatchCache, con: &Connection, request: &Request)
     let request_id = stmt.query_row(
         params![request.method, request.url, request.status_code],
         // Get the ID
-        |row| Ok(row.get(0)?),
+        |row| row.get(0),
     )?;
-    caches.requests_cache.insert(request.to_owned(), request_id);
+    caches
+        .requests_cache
+        .insert(request.clone().into_owned(), request_id);
     Ok(request_id)
 }
 
@@ -177,14 +416,76 @@ fn insert_useragent(caches: &mut BatchCache, con: &Connection, object: &Useragen
     let request_id = stmt.query_row(
         params![object.value],
         // Get the ID
-        |row| Ok(row.get(0)?),
+        |row| row.get(0),
     )?;
+    insert_useragent_details(con, request_id, &object.value)?;
+
     caches
         .useragents_cache
-        .insert(object.to_owned(), request_id);
+        .insert(object.clone().into_owned(), request_id);
     Ok(request_id)
 }
 
+fn insert_useragent_details(con: &Connection, useragent_id: i32, value: &str) -> Result<()> {
+    let details = classify(value);
+    let mut stmt = con.prepare_cached(
+        "
+            INSERT INTO 
+            useragent_details(useragent_id, browser_family, browser_major, os_family, device_type, is_bot) 
+            VALUES(?, ?, ?, ?, ?, ?) 
+        ",
+    )?;
+    stmt.execute(params![
+        useragent_id,
+        details.browser_family,
+        details.browser_major,
+        details.os_family,
+        details.device_type.as_str(),
+        details.is_bot
+    ])?;
+    Ok(())
+}
+
+/// Classifies the useragents inserted before `useragent_details` existed
+fn classify_useragents(con: &Connection) -> Result<()> {
+    let mut stmt = con.prepare(
+        "
+        SELECT ua.id, ua.value FROM useragents ua
+        WHERE NOT EXISTS (SELECT 1 FROM useragent_details d WHERE d.useragent_id = ua.id)
+        ",
+    )?;
+    let useragents = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (useragent_id, value) in useragents {
+        insert_useragent_details(con, useragent_id, &value)?;
+    }
+    Ok(())
+}
+
+fn insert_country(caches: &mut BatchCache, con: &Connection, country: &Country) -> Result<i32> {
+    if let Some(country_id) = caches.countries_cache.get(country) {
+        return Ok(country_id.to_owned());
+    }
+    let mut stmt = con.prepare_cached(
+        "
+            INSERT INTO 
+            countries(code) 
+            VALUES(?)
+            RETURNING id
+            ",
+    )?;
+    let country_id = stmt.query_row(
+        params![country.code],
+        // Get the ID
+        |row| row.get(0),
+    )?;
+    caches
+        .countries_cache
+        .insert(country.to_owned(), country_id);
+    Ok(country_id)
+}
+
 fn insert_user(caches: &mut BatchCache, con: &Connection, object: &User) -> Result<i32> {
     if let Some(request_id) = caches.users_cache.get(object) {
         return Ok(request_id.to_owned());
@@ -195,132 +496,414 @@ fn insert_user(caches: &mut BatchCache, con: &Connection, object: &User) -> Resu
     let useragent_id = object
         .useragent
         .as_ref()
-        .map(|v| insert_useragent(caches, &con, &v))
+        .map(|v| insert_useragent(caches, con, v))
         .transpose()?;
 
+    let country_id = object
+        .country
+        .as_ref()
+        .map(|v| insert_country(caches, con, v))
+        .transpose()?;
+
+    // The hash is the user, also when it is imported again with a country
+    // from `--geoip`, which is set if it was unknown
     let mut stmt = con.prepare_cached(
         "
             INSERT INTO
-            users(hash, useragent_id)
-            VALUES(?, ?)
+            users(hash, useragent_id, country_id)
+            VALUES(?, ?, ?)
+            ON CONFLICT(hash) DO UPDATE SET country_id = IFNULL(country_id, excluded.country_id)
             RETURNING id
         ",
     )?;
     let request_id = stmt.query_row(
-        params![object.hash, useragent_id],
+        params![object.hash, useragent_id, country_id],
         // Get the ID
-        |row| Ok(row.get(0)?),
+        |row| row.get(0),
     )?;
-    caches.users_cache.insert(object.to_owned(), request_id);
+    caches
+        .users_cache
+        .insert(object.clone().into_owned(), request_id);
     Ok(request_id)
 }
 
+fn insert_referrer_domain(caches: &mut BatchCache, con: &Connection, host: &str) -> Result<i32> {
+    if let Some(domain_id) = caches.referrer_domains_cache.get(host) {
+        return Ok(domain_id.to_owned());
+    }
+    let source = classify_host(host);
+    let mut stmt = con.prepare_cached(
+        "
+            INSERT INTO 
+            referrer_domains(host, source_kind, source_name) 
+            VALUES(?, ?, ?)
+            RETURNING id
+            ",
+    )?;
+    let domain_id = stmt.query_row(
+        params![
+            host,
+            source.map(|(kind, _)| kind.as_str()),
+            source.map(|(_, name)| name)
+        ],
+        // Get the ID
+        |row| row.get(0),
+    )?;
+    caches
+        .referrer_domains_cache
+        .insert(host.to_owned(), domain_id);
+    Ok(domain_id)
+}
+
 fn insert_referrer(caches: &mut BatchCache, con: &Connection, referrer: &Referrer) -> Result<i32> {
     if let Some(request_id) = caches.referrer_cache.get(referrer) {
         return Ok(request_id.to_owned());
     }
+    let parts = parse_referrer(&referrer.url);
+    let domain_id = parts
+        .host
+        .as_deref()
+        .map(|host| insert_referrer_domain(caches, con, host))
+        .transpose()?;
     let mut stmt = con.prepare_cached(
         "
             INSERT INTO 
-            referrers(url) 
-            VALUES(?)
+            referrers(url, scheme, domain_id, path) 
+            VALUES(?, ?, ?, ?)
             RETURNING id
             ",
     )?;
     let referrer_id = stmt.query_row(
-        params![referrer.url],
+        params![referrer.url, parts.scheme, domain_id, parts.path],
         // Get the ID
-        |row| Ok(row.get(0)?),
+        |row| row.get(0),
     )?;
     caches
         .referrer_cache
-        .insert(referrer.to_owned(), referrer_id);
+        .insert(referrer.clone().into_owned(), referrer_id);
     Ok(referrer_id)
 }
 
-fn insert_entry(caches: &mut BatchCache, con: &Connection, object: &LogEntry) -> Result<()> {
-    let request_id = insert_request(caches, &con, &object.request)?;
-    let user_id = insert_user(caches, &con, &object.user)?;
+/// Stores the parts of the referrers inserted before they were parsed
+fn parse_referrers(con: &Connection) -> Result<()> {
+    let mut stmt = con.prepare("SELECT id, url FROM referrers")?;
+    let referrers = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<(i32, String)>, _>>()?;
+    let mut caches = BatchCache::new();
+    let mut update =
+        con.prepare("UPDATE referrers SET scheme = ?, domain_id = ?, path = ? WHERE id = ?")?;
+    for (referrer_id, url) in referrers {
+        let parts = parse_referrer(&url);
+        let domain_id = parts
+            .host
+            .as_deref()
+            .map(|host| insert_referrer_domain(&mut caches, con, host))
+            .transpose()?;
+        update.execute(params![parts.scheme, domain_id, parts.path, referrer_id])?;
+    }
+    Ok(())
+}
+
+/// Inserts the entry, or with `Dedup::Count` adds a repeat to the hit count
+/// of the existing one
+fn insert_entry(
+    caches: &mut BatchCache,
+    con: &Connection,
+    object: &LogEntry,
+    dedup: Dedup,
+) -> Result<Msg> {
+    let request_id = insert_request(caches, con, &object.request)?;
+    let user_id = insert_user(caches, con, &object.user)?;
     let referrer_id = object
         .referrer
         .as_ref()
-        .map(|r| insert_referrer(caches, &con, &r))
+        .map(|r| insert_referrer(caches, con, r))
         .transpose()?;
 
     let mut stmt = con.prepare_cached(
         "
             INSERT INTO
-            entrys(timestamp, request_id, user_id, referrer_id)
-            VALUES(?, ?, ?, ?)
+            entrys(timestamp, micros, utc_offset, line_number, source, hit_count, request_id, user_id, referrer_id)
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?)
             ",
     )?;
 
-    stmt.execute(params![object.timestamp, request_id, user_id, referrer_id])
-        .map_err(|err| match err {
-            rusqlite::Error::SqliteFailure(
-                rusqlite::ffi::Error {
-                    code: ErrorCode::ConstraintViolation,
-                    extended_code: _,
-                },
-                _,
-            ) => DbError::DuplicateEntry,
-            er => DbError::SqliteError(er),
-        })?;
-
-    Ok(())
+    let inserted = stmt.execute(params![
+        object.timestamp,
+        object.micros,
+        object.utc_offset,
+        object.line_number,
+        object.source,
+        object.hits,
+        request_id,
+        user_id,
+        referrer_id
+    ]);
+    match inserted {
+        Ok(_) => Ok(Msg::RowInserted),
+        Err(rusqlite::Error::SqliteFailure(err, _))
+            if err.code == ErrorCode::ConstraintViolation && dedup == Dedup::Count =>
+        {
+            let entry_id: i64 = con
+                .prepare_cached(
+                    "
+                    UPDATE entrys SET hit_count = hit_count + ?
+                    WHERE timestamp = ? AND micros = ? AND source = ? AND line_number = ?
+                        AND request_id = ? AND user_id = ?
+                    RETURNING id
+                    ",
+                )?
+                .query_row(
+                    params![
+                        object.hits,
+                        object.timestamp,
+                        object.micros,
+                        object.source,
+                        object.line_number,
+                        request_id,
+                        user_id
+                    ],
+                    |row| row.get(0),
+                )?;
+            add_repeat_hits(con, entry_id, object.hits)?;
+            Ok(Msg::RowCounted)
+        }
+        Err(rusqlite::Error::SqliteFailure(err, _))
+            if err.code == ErrorCode::ConstraintViolation =>
+        {
+            Err(DbError::DuplicateEntry)
+        }
+        Err(err) => Err(DbError::SqliteError(err)),
+    }
 }
 
+/// Inserts the entries and adds them to the rollups and sketches
 pub fn batch_insert(
     msg_sender: &crossbeam_channel::Sender<Msg>,
     con: &Connection,
-    entries: &Vec<LogEntry>,
+    entries: &[LogEntry],
     caches: &mut BatchCache,
+    dedup: Dedup,
 ) -> Result<()> {
+    let last_id = last_entry_id(con)?;
     entries
         .iter()
-        .map(|entry| insert_entry(caches, &con, entry))
-        .send_errors(&msg_sender)
-        .for_each(|_| msg_sender.send(Msg::RowInserted).unwrap());
+        .map(|entry| insert_entry(caches, con, entry, dedup))
+        .send_errors(msg_sender)
+        .for_each(|msg| msg_sender.send(msg).unwrap());
+    update_rollups(con, last_id)?;
+    update_sketches(con, entries, caches)
+}
+
+/// Sets `users.is_bot` for already inserted users
+pub fn mark_bots(con: &Connection, users: &[User], caches: &BatchCache) -> Result<()> {
+    let mut stmt = con.prepare_cached(
+        "
+            UPDATE users SET is_bot = 1 WHERE id = ?
+        ",
+    )?;
+    for user_id in users.iter().filter_map(|u| caches.users_cache.get(u)) {
+        stmt.execute(params![user_id])?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{init, insert_entry, BatchCache};
+    use super::{init, insert_entry, insert_user, migrate, BatchCache, MIGRATIONS};
+    use crate::importer::{Dedup, Importer, Msg};
     use crate::models::*;
     use itertools::Itertools;
+    use rusqlite::Connection;
+
+    /// Tables of the first version, before any of the migrations
+    const SCHEMA_V0: &str = "
+        CREATE TABLE entrys (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          timestamp BIGINT NOT NULL,
+          request_id INTEGER NOT NULL,
+          user_id INTEGER NOT NULL,
+          referrer_id INTEGER,
+          UNIQUE (timestamp, request_id, user_id)
+        );
+        CREATE INDEX entrys_cols ON entrys(timestamp, request_id, user_id);
+        CREATE TABLE requests (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          method TEXT NOT NULL,
+          url TEXT NOT NULL,
+          status_code INTEGER NOT NULL,
+          UNIQUE (method, url, status_code)
+        );
+        CREATE TABLE users (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          hash BIGINT UNIQUE,
+          useragent_id INTEGER
+        );
+        CREATE INDEX users_cols ON users(hash, useragent_id);
+        CREATE TABLE useragents (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          value TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE referrers (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          url TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE countries (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          code TEXT NOT NULL UNIQUE
+        );
+        INSERT INTO requests(method, url, status_code) VALUES ('GET', '/', 200);
+        INSERT INTO useragents(value) VALUES ('Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/94.0.4606.81 Safari/537.36');
+        INSERT INTO users(hash, useragent_id) VALUES (123, 1);
+        INSERT INTO referrers(url) VALUES ('https://www.google.com/search?q=foo');
+        INSERT INTO entrys(timestamp, request_id, user_id, referrer_id) VALUES (100, 1, 1, 1);
+    ";
+
+    fn columns(con: &Connection, table: &str) -> Vec<String> {
+        con.prepare("SELECT name FROM pragma_table_info(?)")
+            .unwrap()
+            .query_map([table], |row| row.get(0))
+            .unwrap()
+            .flatten()
+            .collect()
+    }
 
     #[test]
     fn test_init_schema() {
         let _ = init(":memory:").unwrap();
     }
 
+    #[test]
+    fn test_migrate() {
+        let mut con = Connection::open_in_memory().unwrap();
+        con.execute_batch(SCHEMA_V0).unwrap();
+        migrate(&mut con).unwrap();
+        assert_eq!(
+            vec!["id", "hash", "useragent_id", "country_id", "is_bot"],
+            columns(&con, "users")
+        );
+        assert_eq!(
+            vec![
+                "id",
+                "timestamp",
+                "micros",
+                "utc_offset",
+                "line_number",
+                "source",
+                "hit_count",
+                "request_id",
+                "user_id",
+                "referrer_id",
+                "session_id"
+            ],
+            columns(&con, "entrys")
+        );
+        assert_eq!(
+            vec!["id", "url", "scheme", "domain_id", "path"],
+            columns(&con, "referrers")
+        );
+        let version: usize = con
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len(), version);
+
+        // Applied only once
+        migrate(&mut con).unwrap();
+    }
+
+    #[test]
+    fn test_init_existing_database() {
+        let path = std::env::temp_dir().join("loggerson_test_db_existing.db");
+        let db_path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(SCHEMA_V0)
+            .unwrap();
+
+        // The old entry is kept and counted to the rollups and sketches, and
+        // its useragent classified and referrer parsed
+        let con = init(&db_path).unwrap().get().unwrap();
+        let (timestamp, hit_count, hits, days): (i64, i64, i64, i64) = con
+            .query_row(
+                "
+                SELECT e.timestamp, e.hit_count,
+                    (SELECT SUM(hits) FROM rollup_requests WHERE period = 'day'),
+                    (SELECT COUNT(*) FROM sketches_daily)
+                FROM entrys e
+                ",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((100, 1, 1, 1), (timestamp, hit_count, hits, days));
+        let browser: String = con
+            .query_row(
+                "SELECT browser_family FROM rollup_browsers WHERE period = 'day'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!("Chrome", browser);
+        let referrer: (String, String, String) = con
+            .query_row(
+                "
+                SELECT rr.scheme, rr.path, d.source_name
+                FROM referrers rr, referrer_domains d WHERE rr.domain_id = d.id
+                ",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (
+                "https".to_owned(),
+                "/search?q=foo".to_owned(),
+                "Google".to_owned()
+            ),
+            referrer
+        );
+
+        let line =
+            r#"1.2.3.4 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "curl/7.68.0""#;
+        let mut inserted = 0;
+        Importer::new(&db_path)
+            .dedup(Dedup::Line)
+            .run([Ok(line)], |msg| {
+                if let Msg::RowInserted = msg {
+                    inserted += 1;
+                }
+            })
+            .unwrap();
+        assert_eq!(1, inserted);
+        let entries: i64 = con
+            .query_row("SELECT COUNT(*) FROM entrys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(2, entries);
+
+        drop(con);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(format!("{}-wal", db_path));
+        let _ = std::fs::remove_file(format!("{}-shm", db_path));
+    }
+
     #[test]
     fn test_insert_entry() {
         let con = init(":memory:").unwrap().get().unwrap();
         let mut caches = BatchCache::new();
-        insert_entry(
-            &mut caches,
-            &con,
-            &LogEntry {
-                timestamp: 100,
-                request: Request {
-                    method: "GET".to_owned(),
-                    url: "https://example.com".to_owned(),
-                    status_code: 300,
-                },
-                user: User {
-                    hash: Some(123),
-                    useragent: Some(Useragent {
-                        value: "Foo".to_owned(),
-                    }),
-                },
-                referrer: Some(Referrer {
-                    url: "https://test".to_owned(),
-                }),
-            },
-        )
-        .unwrap();
+        let mut entry = LogEntry::test(100, 123, "https://example.com");
+        entry.request.status_code = 300;
+        entry.user.useragent = Some(Useragent {
+            value: "Foo".to_owned().into(),
+        });
+        entry.user.country = Some(Country {
+            code: "FI".to_owned(),
+        });
+        entry.referrer = Some(Referrer {
+            url: "https://test".to_owned().into(),
+        });
+        insert_entry(&mut caches, &con, &entry, Dedup::Strict).unwrap();
 
         let mut stmt = con
             .prepare(
@@ -329,25 +912,28 @@ mod tests {
                     e.timestamp, u.hash, 
                     r.method, r.url, r.status_code, 
                     ua.value as useragent, 
-                    rr.url as referrer_url 
+                    rr.url as referrer_url,
+                    c.code as country_code
                 FROM 
                     entrys e, 
                     requests r, 
                     users u, 
                     useragents ua, 
-                    referrers rr
+                    referrers rr,
+                    countries c
                 WHERE
                     e.request_id = r.id AND
                     e.user_id = u.id AND
                     e.referrer_id = rr.id AND
-                    u.useragent_id = ua.id
+                    u.useragent_id = ua.id AND
+                    u.country_id = c.id
             ",
             )
             .unwrap();
 
         let rows = stmt
             .query_map([], |f| {
-                let values: (i64, i64, String, String, i32, String, String) = (
+                let values: (i64, i64, String, String, i32, String, String, String) = (
                     f.get(0)?,
                     f.get(1)?,
                     f.get(2)?,
@@ -355,6 +941,7 @@ mod tests {
                     f.get(4)?,
                     f.get(5)?,
                     f.get(6)?,
+                    f.get(7)?,
                 );
                 Ok(values)
             })
@@ -369,7 +956,8 @@ mod tests {
                 "https://example.com".to_owned(),
                 300,
                 "Foo".to_owned(),
-                "https://test".to_owned()
+                "https://test".to_owned(),
+                "FI".to_owned()
             )],
             rows
         );
@@ -378,23 +966,42 @@ mod tests {
     fn test_insert_entry_nulls() {
         let con = init(":memory:").unwrap().get().unwrap();
         let mut caches = BatchCache::new();
-        insert_entry(
-            &mut caches,
-            &con,
-            &LogEntry {
-                timestamp: 100,
-                request: Request {
-                    method: "GET".to_owned(),
-                    url: "https://example.com".to_owned(),
-                    status_code: 300,
-                },
-                user: User {
-                    hash: Some(123),
-                    useragent: None,
-                },
-                referrer: None,
-            },
-        )
-        .unwrap();
+        let mut entry = LogEntry::test(100, 123, "https://example.com");
+        entry.request.status_code = 300;
+        insert_entry(&mut caches, &con, &entry, Dedup::Strict).unwrap();
+    }
+
+    #[test]
+    fn test_insert_user_country() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let user = |country: Option<&str>| User {
+            hash: Some(123),
+            useragent: None,
+            country: country.map(|code| Country {
+                code: code.to_owned(),
+            }),
+        };
+        let country = || -> Option<String> {
+            con.query_row(
+                "SELECT c.code FROM users u LEFT JOIN countries c ON u.country_id = c.id",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let id = insert_user(&mut BatchCache::new(), &con, &user(None)).unwrap();
+
+        // Imported again with `--geoip`, and then with changed GeoIP data
+        let mut caches = BatchCache::new();
+        assert_eq!(
+            id,
+            insert_user(&mut caches, &con, &user(Some("FI"))).unwrap()
+        );
+        assert_eq!(Some("FI".to_owned()), country());
+        assert_eq!(
+            id,
+            insert_user(&mut caches, &con, &user(Some("SE"))).unwrap()
+        );
+        assert_eq!(Some("FI".to_owned()), country());
     }
 }
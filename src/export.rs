@@ -0,0 +1,419 @@
+//! Streams the entries joined with all dimensions out of the database
+
+use crate::report::{csv_escape, parse_time_arg, to_json, to_text};
+use clap::{Args, ValueEnum};
+use derive_more::From;
+use itertools::Itertools;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// Columnar Parquet file, rows are written in groups
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// SQLite database file
+    #[arg(long, default_value = ".cache.db")]
+    pub db: String,
+
+    /// Start of the time range, inclusive. RFC 3339 time or `YYYY-MM-DD`
+    #[arg(long, value_parser = parse_time_arg)]
+    pub from: Option<i64>,
+
+    /// End of the time range, exclusive. RFC 3339 time or `YYYY-MM-DD`
+    #[arg(long, value_parser = parse_time_arg)]
+    pub to: Option<i64>,
+
+    /// Leave out users marked as bots
+    #[arg(long)]
+    pub exclude_bots: bool,
+
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    pub format: ExportFormat,
+
+    /// Output file, standard output by default
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(From, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ExportError {
+    SqliteError(rusqlite::Error),
+    IoError(io::Error),
+    #[cfg(feature = "parquet")]
+    ParquetError(parquet::errors::ParquetError),
+    #[cfg(feature = "parquet")]
+    ArrowError(arrow_schema::ArrowError),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::SqliteError(err) => write!(f, "{}", err),
+            ExportError::IoError(err) => write!(f, "{}", err),
+            #[cfg(feature = "parquet")]
+            ExportError::ParquetError(err) => write!(f, "{}", err),
+            #[cfg(feature = "parquet")]
+            ExportError::ArrowError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ColumnType {
+    Integer,
+    /// Unix timestamp in seconds, UTC
+    Timestamp,
+    Text,
+    Boolean,
+}
+
+/// Columns of the export, in the order of `SELECT_ENTRIES`
+static COLUMNS: &[(&str, ColumnType)] = &[
+    ("id", ColumnType::Integer),
+    ("timestamp", ColumnType::Timestamp),
+    ("micros", ColumnType::Integer),
+    ("utc_offset", ColumnType::Integer),
+    ("method", ColumnType::Text),
+    ("url", ColumnType::Text),
+    ("status_code", ColumnType::Integer),
+    ("hit_count", ColumnType::Integer),
+    ("user_id", ColumnType::Integer),
+    ("user_hash", ColumnType::Integer),
+    ("useragent", ColumnType::Text),
+    ("browser_family", ColumnType::Text),
+    ("browser_major", ColumnType::Text),
+    ("os_family", ColumnType::Text),
+    ("device_type", ColumnType::Text),
+    ("country", ColumnType::Text),
+    ("is_bot", ColumnType::Boolean),
+    ("session_id", ColumnType::Integer),
+    ("referrer", ColumnType::Text),
+    ("referrer_host", ColumnType::Text),
+    ("referrer_source_kind", ColumnType::Text),
+    ("referrer_source_name", ColumnType::Text),
+    ("referrer_internal", ColumnType::Boolean),
+];
+
+static SELECT_ENTRIES: &str = "
+    SELECT e.id, e.timestamp, e.micros, e.utc_offset, r.method, r.url, r.status_code, e.hit_count,
+        e.user_id, u.hash, ua.value,
+        d.browser_family, d.browser_major, d.os_family, d.device_type,
+        c.code, u.is_bot, e.session_id,
+        rr.url, rd.host, rd.source_kind, rd.source_name, rd.is_internal
+    FROM entrys e
+    JOIN requests r ON e.request_id = r.id
+    JOIN users u ON e.user_id = u.id
+    LEFT JOIN useragents ua ON u.useragent_id = ua.id
+    LEFT JOIN useragent_details d ON u.useragent_id = d.useragent_id
+    LEFT JOIN countries c ON u.country_id = c.id
+    LEFT JOIN referrers rr ON e.referrer_id = rr.id
+    LEFT JOIN referrer_domains rd ON rr.domain_id = rd.id
+    WHERE e.timestamp >= ? AND e.timestamp < ?
+";
+
+/// Writes the entries of the range ordered by time, one row at a time.
+/// Returns the number of rows written.
+pub fn run(
+    con: &Connection,
+    args: &ExportArgs,
+    out: &mut (impl Write + Send),
+) -> Result<usize, ExportError> {
+    let from = args.from.unwrap_or(i64::MIN);
+    let to = args.to.unwrap_or(i64::MAX);
+    let bots = if args.exclude_bots {
+        "AND u.is_bot = 0"
+    } else {
+        ""
+    };
+
+    let mut stmt = con.prepare(&format!(
+        "{} {} ORDER BY e.timestamp, e.id",
+        SELECT_ENTRIES, bots
+    ))?;
+    let mut rows = stmt.query(params![from, to])?;
+    let mut next_row = || -> Result<Option<Vec<Value>>, ExportError> {
+        Ok(match rows.next()? {
+            Some(row) => Some(
+                (0..COLUMNS.len())
+                    .map(|i| row.get::<_, Value>(i))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        })
+    };
+
+    let mut written = 0;
+    match args.format {
+        ExportFormat::Csv => {
+            writeln!(out, "{}", COLUMNS.iter().map(|(name, _)| name).join(","))?;
+            while let Some(row) = next_row()? {
+                writeln!(
+                    out,
+                    "{}",
+                    row.iter().map(|v| csv_escape(&to_text(v))).join(",")
+                )?;
+                written += 1;
+            }
+        }
+        ExportFormat::Ndjson => {
+            while let Some(row) = next_row()? {
+                let object = COLUMNS
+                    .iter()
+                    .zip(row)
+                    .map(|((name, column_type), value)| {
+                        let value = match (column_type, value) {
+                            (ColumnType::Boolean, Value::Integer(i)) => (i != 0).into(),
+                            (_, value) => to_json(&value),
+                        };
+                        (name.to_string(), value)
+                    })
+                    .collect::<serde_json::Map<_, _>>();
+                serde_json::to_writer(&mut *out, &object).map_err(io::Error::from)?;
+                writeln!(out)?;
+                written += 1;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            let mut writer = parquet_output::ParquetOutput::new(&mut *out)?;
+            while let Some(row) = next_row()? {
+                writer.push(row)?;
+                written += 1;
+            }
+            writer.finish()?;
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_output {
+    use super::{ColumnType, ExportError, COLUMNS};
+    use arrow_array::builder::{
+        ArrayBuilder, BooleanBuilder, Int64Builder, StringBuilder, TimestampSecondBuilder,
+    };
+    use arrow_array::RecordBatch;
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use parquet::arrow::ArrowWriter;
+    use rusqlite::types::Value;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    /// Rows are buffered to a record batch of this size before writing
+    const BATCH_ROWS: usize = 64 * 1024;
+
+    pub struct ParquetOutput<W: Write + Send> {
+        writer: ArrowWriter<W>,
+        schema: Arc<Schema>,
+        builders: Vec<Box<dyn ArrayBuilder>>,
+    }
+
+    impl<W: Write + Send> ParquetOutput<W> {
+        pub fn new(out: W) -> Result<Self, ExportError> {
+            let fields = COLUMNS
+                .iter()
+                .map(|(name, column_type)| {
+                    let data_type = match column_type {
+                        ColumnType::Integer => DataType::Int64,
+                        ColumnType::Timestamp => {
+                            DataType::Timestamp(TimeUnit::Second, Some("UTC".into()))
+                        }
+                        ColumnType::Text => DataType::Utf8,
+                        ColumnType::Boolean => DataType::Boolean,
+                    };
+                    Field::new(*name, data_type, true)
+                })
+                .collect::<Vec<_>>();
+            let schema = Arc::new(Schema::new(fields));
+            let writer = ArrowWriter::try_new(out, schema.clone(), None)?;
+            Ok(ParquetOutput {
+                writer,
+                schema,
+                builders: new_builders(),
+            })
+        }
+
+        pub fn push(&mut self, row: Vec<Value>) -> Result<(), ExportError> {
+            for ((builder, (_, column_type)), value) in
+                self.builders.iter_mut().zip(COLUMNS).zip(row)
+            {
+                let any = builder.as_any_mut();
+                match column_type {
+                    ColumnType::Integer => {
+                        let builder = any.downcast_mut::<Int64Builder>().unwrap();
+                        match value {
+                            Value::Integer(i) => builder.append_value(i),
+                            _ => builder.append_null(),
+                        }
+                    }
+                    ColumnType::Timestamp => {
+                        let builder = any.downcast_mut::<TimestampSecondBuilder>().unwrap();
+                        match value {
+                            Value::Integer(i) => builder.append_value(i),
+                            _ => builder.append_null(),
+                        }
+                    }
+                    ColumnType::Text => {
+                        let builder = any.downcast_mut::<StringBuilder>().unwrap();
+                        match value {
+                            Value::Text(s) => builder.append_value(s),
+                            _ => builder.append_null(),
+                        }
+                    }
+                    ColumnType::Boolean => {
+                        let builder = any.downcast_mut::<BooleanBuilder>().unwrap();
+                        match value {
+                            Value::Integer(i) => builder.append_value(i != 0),
+                            _ => builder.append_null(),
+                        }
+                    }
+                }
+            }
+            if self.builders[0].len() >= BATCH_ROWS {
+                self.write_batch()?;
+            }
+            Ok(())
+        }
+
+        fn write_batch(&mut self) -> Result<(), ExportError> {
+            let columns = self.builders.iter_mut().map(|b| b.finish()).collect();
+            let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+            self.writer.write(&batch)?;
+            Ok(())
+        }
+
+        pub fn finish(mut self) -> Result<(), ExportError> {
+            if !self.builders[0].is_empty() {
+                self.write_batch()?;
+            }
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+
+    fn new_builders() -> Vec<Box<dyn ArrayBuilder>> {
+        COLUMNS
+            .iter()
+            .map(|(_, column_type)| -> Box<dyn ArrayBuilder> {
+                match column_type {
+                    ColumnType::Integer => Box::new(Int64Builder::new()),
+                    ColumnType::Timestamp => {
+                        Box::new(TimestampSecondBuilder::new().with_timezone("UTC"))
+                    }
+                    ColumnType::Text => Box::new(StringBuilder::new()),
+                    ColumnType::Boolean => Box::new(BooleanBuilder::new()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, ExportArgs, ExportFormat};
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::importer::Dedup;
+    use crate::models::*;
+
+    fn entry(timestamp: i64, url: &str, referrer: Option<&str>) -> LogEntry<'static> {
+        let mut entry = LogEntry::test(timestamp, 1, url);
+        entry.user.country = Some(Country {
+            code: "FI".to_owned(),
+        });
+        entry.referrer = referrer.map(|url| Referrer {
+            url: url.to_owned().into(),
+        });
+        entry
+    }
+
+    fn args(format: ExportFormat) -> ExportArgs {
+        ExportArgs {
+            db: ":memory:".to_owned(),
+            from: Some(10),
+            to: None,
+            exclude_bots: false,
+            format,
+            output: None,
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut entries = vec![
+            entry(0, "/old", None),
+            entry(20, "/a,b", Some("https://www.google.com/")),
+            entry(10, "/", None),
+        ];
+        entries[1].micros = 250_000;
+        entries[1].utc_offset = 7200;
+        batch_insert(&sender, &con, &entries, &mut caches, Dedup::Strict).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(2, run(&con, &args(ExportFormat::Csv), &mut out).unwrap());
+        let csv = String::from_utf8(out).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("id,timestamp,micros,utc_offset,method,url,status_code,"));
+        assert_eq!("3,10,0,0,GET,/,200,1,1,1,,,,,,FI,0,,,,,,", lines[1]);
+        assert_eq!(
+            "2,20,250000,7200,GET,\"/a,b\",200,1,1,1,,,,,,FI,0,,https://www.google.com/,www.google.com,search,Google,0",
+            lines[2]
+        );
+
+        let mut out = Vec::new();
+        run(&con, &args(ExportFormat::Ndjson), &mut out).unwrap();
+        let ndjson = String::from_utf8(out).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(ndjson.lines().next().unwrap()).unwrap();
+        assert_eq!("/", first["url"]);
+        assert_eq!(0, first["utc_offset"]);
+        assert_eq!(false, first["is_bot"]);
+        assert_eq!(serde_json::Value::Null, first["referrer"]);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_export_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let entries = vec![entry(10, "/", None), entry(20, "/a", None)];
+        batch_insert(&sender, &con, &entries, &mut caches, Dedup::Strict).unwrap();
+
+        let path = std::env::temp_dir().join("loggerson_test_export.parquet");
+        let mut out = std::fs::File::create(&path).unwrap();
+        assert_eq!(
+            2,
+            run(&con, &args(ExportFormat::Parquet), &mut out).unwrap()
+        );
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(2, metadata.file_metadata().num_rows());
+        let schema = metadata.file_metadata().schema_descr();
+        assert_eq!("timestamp", schema.column(1).name());
+        assert_eq!("micros", schema.column(2).name());
+        assert_eq!("utc_offset", schema.column(3).name());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+//! Include filters for imported rows, e.g.
+//!
+//! ```text
+//! status >= 200 && status < 400
+//! method == "GET" && url !~ "\.(css|js|png)$"
+//! time >= "2021-10-01" && time < "2021-11-01T12:00:00+02:00"
+//! ```
+//!
+//! Fields are `status`, `time` (alias `timestamp`), `method`, `url`,
+//! `useragent`, `referrer` and `country`. Missing values compare as empty
+//! string. Operators are `== != < <= > >=`, regex `=~ !~`, `&& || !` and
+//! parentheses.
+
+use crate::models::LogEntry;
+use chrono::{DateTime, NaiveDate};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+#[derive(Debug)]
+pub struct FilterError(String);
+
+impl std::error::Error for FilterError {}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid filter: {}", self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?.into_iter().peekable(),
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.tokens.next() {
+            return Err(FilterError(format!("unexpected {:?}", token)));
+        }
+        Ok(Filter { expr })
+    }
+
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.expr.eval(entry)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+    Matches(Field, Regex, bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Status,
+    Timestamp,
+    Method,
+    Url,
+    Useragent,
+    Referrer,
+    Country,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Number(i64),
+    Text(String),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Result<Self, FilterError> {
+        Ok(match name {
+            "status" => Field::Status,
+            "time" | "timestamp" => Field::Timestamp,
+            "method" => Field::Method,
+            "url" => Field::Url,
+            "useragent" => Field::Useragent,
+            "referrer" => Field::Referrer,
+            "country" => Field::Country,
+            _ => return Err(FilterError(format!("unknown field '{}'", name))),
+        })
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Field::Status | Field::Timestamp)
+    }
+
+    fn number(&self, entry: &LogEntry) -> i64 {
+        match self {
+            Field::Status => entry.request.status_code as i64,
+            Field::Timestamp => entry.timestamp,
+            _ => 0,
+        }
+    }
+
+    fn text<'a>(&self, entry: &'a LogEntry) -> &'a str {
+        match self {
+            Field::Method => &entry.request.method,
+            Field::Url => &entry.request.url,
+            Field::Useragent => entry.user.useragent.as_ref().map_or("", |u| &u.value),
+            Field::Referrer => entry.referrer.as_ref().map_or("", |r| &r.url),
+            Field::Country => entry.user.country.as_ref().map_or("", |c| &c.code),
+            _ => "",
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, entry: &LogEntry) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(entry) && b.eval(entry),
+            Expr::Or(a, b) => a.eval(entry) || b.eval(entry),
+            Expr::Not(a) => !a.eval(entry),
+            Expr::Compare(field, op, value) => {
+                let ordering = match value {
+                    Value::Number(n) => field.number(entry).cmp(n),
+                    Value::Text(s) => field.text(entry).cmp(s.as_str()),
+                };
+                match op {
+                    Op::Eq => ordering == Ordering::Equal,
+                    Op::Ne => ordering != Ordering::Equal,
+                    Op::Lt => ordering == Ordering::Less,
+                    Op::Le => ordering != Ordering::Greater,
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                }
+            }
+            Expr::Matches(field, regex, negate) => regex.is_match(field.text(entry)) != *negate,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Text(String),
+    Op(Op),
+    Match,
+    NotMatch,
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+            {
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+            continue;
+        }
+        if c.is_ascii_digit() || c == '-' {
+            let mut number = String::from(c);
+            chars.next();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| FilterError(format!("invalid number '{}'", number)))?;
+            tokens.push(Token::Number(number));
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    // Only quotes are escaped, so regex escapes can be written as is
+                    Some('\\') if chars.peek() == Some(&'"') => text.push(chars.next().unwrap()),
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => return Err(FilterError("unterminated string".to_owned())),
+                }
+            }
+            tokens.push(Token::Text(text));
+            continue;
+        }
+
+        chars.next();
+        let next = chars.peek().copied();
+        let (token, two_chars) = match (c, next) {
+            ('=', Some('=')) => (Token::Op(Op::Eq), true),
+            ('=', Some('~')) => (Token::Match, true),
+            ('!', Some('=')) => (Token::Op(Op::Ne), true),
+            ('!', Some('~')) => (Token::NotMatch, true),
+            ('<', Some('=')) => (Token::Op(Op::Le), true),
+            ('>', Some('=')) => (Token::Op(Op::Ge), true),
+            ('&', Some('&')) => (Token::And, true),
+            ('|', Some('|')) => (Token::Or, true),
+            ('<', _) => (Token::Op(Op::Lt), false),
+            ('>', _) => (Token::Op(Op::Gt), false),
+            ('!', _) => (Token::Not, false),
+            ('(', _) => (Token::LParen, false),
+            (')', _) => (Token::RParen, false),
+            _ => return Err(FilterError(format!("unexpected character '{}'", c))),
+        };
+        if two_chars {
+            chars.next();
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        self.tokens.next()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, FilterError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(FilterError("missing ')'".to_owned())),
+                }
+            }
+            Some(Token::Ident(name)) => self.parse_comparison(Field::from_name(&name)?),
+            Some(token) => Err(FilterError(format!("unexpected {:?}", token))),
+            None => Err(FilterError("unexpected end".to_owned())),
+        }
+    }
+
+    fn parse_comparison(&mut self, field: Field) -> Result<Expr, FilterError> {
+        let token = self.next();
+        let value = self.next();
+        match (token, value) {
+            (Some(Token::Match | Token::NotMatch), _) if field.is_numeric() => Err(FilterError(
+                format!("regex match on numeric field {:?}", field),
+            )),
+            (Some(token @ (Token::Match | Token::NotMatch)), Some(Token::Text(pattern))) => {
+                let regex = Regex::new(&pattern).map_err(|err| FilterError(err.to_string()))?;
+                Ok(Expr::Matches(field, regex, token == Token::NotMatch))
+            }
+            (Some(Token::Op(op)), Some(Token::Number(n))) if field.is_numeric() => {
+                Ok(Expr::Compare(field, op, Value::Number(n)))
+            }
+            (Some(Token::Op(op)), Some(Token::Text(text))) if field.is_numeric() => {
+                let timestamp = parse_time(&text)
+                    .ok_or_else(|| FilterError(format!("invalid time '{}'", text)))?;
+                Ok(Expr::Compare(field, op, Value::Number(timestamp)))
+            }
+            (Some(Token::Op(op)), Some(Token::Text(text))) => {
+                Ok(Expr::Compare(field, op, Value::Text(text)))
+            }
+            (Some(Token::Op(_)), value) => Err(FilterError(format!(
+                "invalid value {:?} for field {:?}",
+                value, field
+            ))),
+            (token, _) => Err(FilterError(format!(
+                "expected operator after field {:?}, got {:?}",
+                field, token
+            ))),
+        }
+    }
+}
+
+/// Accepts RFC 3339 timestamps and plain `YYYY-MM-DD` dates (as UTC)
+pub fn parse_time(text: &str) -> Option<i64> {
+    if let Ok(dtime) = DateTime::parse_from_rfc3339(text) {
+        return Some(dtime.timestamp());
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::models::*;
+
+    fn entry() -> LogEntry<'static> {
+        // 2021-10-10T11:55:36Z
+        let mut entry = LogEntry::test(1633866936, 123, "/static/app.css?v=1");
+        entry.user.country = Some(Country {
+            code: "FI".to_owned(),
+        });
+        entry
+    }
+
+    fn matches(filter: &str) -> bool {
+        Filter::parse(filter).unwrap().matches(&entry())
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        assert!(matches("status >= 200 && status < 400"));
+        assert!(!matches("status != 200"));
+        assert!(matches(r#"method == "GET""#));
+        assert!(!matches(r#"url !~ "\.(css|js|png)(\?|$)""#));
+        assert!(matches(r#"url =~ "^/static/""#));
+        assert!(matches(
+            r#"time >= "2021-10-10" && time < "2021-10-10T12:00:00Z""#
+        ));
+        assert!(!matches(r#"time < "2021-10-10T13:00:00+02:00""#));
+        assert!(matches(r#"referrer == "" && country == "FI""#));
+        assert!(matches(r#"!(status == 404 || method == "POST")"#));
+        assert!(matches(
+            r#"status == 404 || status == 200 && method == "GET""#
+        ));
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        assert!(Filter::parse("foo == 1").is_err());
+        assert!(Filter::parse("status =~ \"2..\"").is_err());
+        assert!(Filter::parse("status ==").is_err());
+        assert!(Filter::parse("status == 200 &&").is_err());
+        assert!(Filter::parse("(status == 200").is_err());
+        assert!(Filter::parse("time > \"yesterday\"").is_err());
+        assert!(Filter::parse("url == \"foo").is_err());
+        assert!(Filter::parse("status == 200 status").is_err());
+    }
+}
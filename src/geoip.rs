@@ -0,0 +1,150 @@
+use crate::models::Country;
+use derive_more::From;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::{fs::File, io};
+
+#[derive(From, Debug)]
+pub enum GeoIpError {
+    IoError(io::Error),
+    MaxMindDbError(maxminddb::MaxMindDbError),
+    InvalidRange(String),
+}
+
+impl std::error::Error for GeoIpError {}
+
+impl std::fmt::Display for GeoIpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeoIpError::IoError(err) => write!(f, "{}", err),
+            GeoIpError::MaxMindDbError(err) => write!(f, "{}", err),
+            GeoIpError::InvalidRange(line) => write!(f, "Invalid IP range line '{}'", line),
+        }
+    }
+}
+
+/// Offline IP to country lookup
+///
+/// Either a MaxMind `.mmdb` file (e.g. GeoLite2-Country) or a CSV range file
+/// with lines of `start_ip,end_ip,country_code` (e.g. db-ip.com lite CSV).
+pub enum GeoIp {
+    Mmdb(maxminddb::Reader<Vec<u8>>),
+    Ranges(Vec<IpRange>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct IpRange {
+    start: u128,
+    end: u128,
+    code: String,
+}
+
+impl GeoIp {
+    /// Opens the database, files ending with `.mmdb` are read as MaxMind
+    /// databases, anything else as CSV range file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "mmdb") {
+            Ok(GeoIp::Mmdb(maxminddb::Reader::open_readfile(path)?))
+        } else {
+            GeoIp::from_csv(BufReader::new(File::open(path)?))
+        }
+    }
+
+    pub fn from_csv(reader: impl BufRead) -> Result<Self, GeoIpError> {
+        let mut ranges = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let cols = line
+                .split(',')
+                .map(|col| col.trim().trim_matches('"'))
+                .collect::<Vec<_>>();
+            if let [start, end, code, ..] = cols[..] {
+                let (start, end) = match (start.parse::<IpAddr>(), end.parse::<IpAddr>()) {
+                    (Ok(start), Ok(end)) => (ip_to_u128(start), ip_to_u128(end)),
+                    _ => return Err(GeoIpError::InvalidRange(line)),
+                };
+                ranges.push(IpRange {
+                    start,
+                    end,
+                    code: code.to_owned(),
+                });
+            } else {
+                return Err(GeoIpError::InvalidRange(line));
+            }
+        }
+        ranges.sort_by_key(|r| r.start);
+        Ok(GeoIp::Ranges(ranges))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Country> {
+        match self {
+            GeoIp::Mmdb(reader) => {
+                let result = reader.lookup(ip).ok()?;
+                let country: maxminddb::geoip2::Country = result.decode().ok()??;
+                country.country.iso_code.map(|code| Country {
+                    code: code.to_owned(),
+                })
+            }
+            GeoIp::Ranges(ranges) => {
+                let ip = ip_to_u128(ip);
+
+                // Last range starting before or at the ip
+                let idx = ranges.partition_point(|r| r.start <= ip);
+                let range = ranges.get(idx.checked_sub(1)?)?;
+                (ip <= range.end).then(|| Country {
+                    code: range.code.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// IPv4 addresses are mapped to IPv6 space so both can share one sorted list
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GeoIp;
+    use crate::models::Country;
+
+    #[test]
+    fn test_csv_lookup() {
+        let csv = "\
+            1.0.0.0,1.0.0.255,AU\n\
+            \"1.0.4.0\",\"1.0.7.255\",\"AU\"\n\
+            5.0.0.0,5.255.255.255,FI\n\
+            2001:db8::,2001:db8::ffff,SE\n";
+        let geoip = GeoIp::from_csv(csv.as_bytes()).unwrap();
+        let lookup = |ip: &str| geoip.lookup(ip.parse().unwrap()).map(|c| c.code);
+
+        assert_eq!(Some("AU".to_owned()), lookup("1.0.0.1"));
+        assert_eq!(Some("AU".to_owned()), lookup("1.0.5.1"));
+        assert_eq!(None, lookup("1.0.2.1"));
+        assert_eq!(Some("FI".to_owned()), lookup("5.1.2.3"));
+        assert_eq!(Some("SE".to_owned()), lookup("2001:db8::1"));
+        assert_eq!(None, lookup("0.0.0.1"));
+        assert_eq!(None, lookup("10.0.0.1"));
+        assert_eq!(
+            Some(Country {
+                code: "FI".to_owned()
+            }),
+            geoip.lookup("5.255.255.255".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_csv_invalid_range() {
+        assert!(GeoIp::from_csv("1.0.0.0,foo,AU\n".as_bytes()).is_err());
+        assert!(GeoIp::from_csv("1.0.0.0\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+
+/// Number of index bits, the sketch has `2^PRECISION` one byte registers
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Most registers with a value kept sparse, as 3 bytes each they take less
+/// than the dense registers
+const SPARSE_MAX: usize = REGISTERS / 3;
+
+/// HyperLogLog sketch for approximate distinct counts, the standard error is
+/// about 1.6 %. Sketches are merged by taking the maximum of each register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Registers,
+}
+
+/// Sketches of a few users, e.g. of most requests on a day, keep only the
+/// registers with a value
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Registers {
+    /// Index and value, sorted by the index
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog {
+            registers: Registers::Sparse(Vec::new()),
+        }
+    }
+
+    /// Sketch stored with `to_bytes`, `None` if it isn't one. Dense ones
+    /// with few registers set, as stored by earlier versions, become sparse.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() == REGISTERS {
+            let set = bytes.iter().filter(|r| **r != 0).count();
+            let registers = if set <= SPARSE_MAX {
+                let pairs = bytes.iter().enumerate().filter(|(_, r)| **r != 0);
+                Registers::Sparse(pairs.map(|(index, r)| (index as u16, *r)).collect())
+            } else {
+                Registers::Dense(bytes.to_vec())
+            };
+            return Some(HyperLogLog { registers });
+        }
+        if !bytes.len().is_multiple_of(3) || bytes.len() / 3 > SPARSE_MAX {
+            return None;
+        }
+        let pairs = bytes
+            .chunks_exact(3)
+            .map(|pair| (u16::from_be_bytes([pair[0], pair[1]]), pair[2]))
+            .collect::<Vec<_>>();
+        let sorted = pairs.windows(2).all(|w| w[0].0 < w[1].0);
+        let in_range = pairs
+            .last()
+            .is_none_or(|&(index, _)| (index as usize) < REGISTERS);
+        (sorted && in_range).then_some(HyperLogLog {
+            registers: Registers::Sparse(pairs),
+        })
+    }
+
+    /// The registers, or for a sparse sketch 3 bytes per register with a
+    /// value: the index big-endian and the value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.registers {
+            Registers::Sparse(pairs) => pairs
+                .iter()
+                .flat_map(|&(index, rank)| {
+                    let [high, low] = index.to_be_bytes();
+                    [high, low, rank]
+                })
+                .collect(),
+            Registers::Dense(registers) => registers.clone(),
+        }
+    }
+
+    /// Adds the user hash. The hash is mixed again, so hashes which are
+    /// truncated or otherwise not uniform still spread over the registers.
+    pub fn insert(&mut self, hash: i64) {
+        let x = mix(hash as u64);
+        let index = (x >> (64 - PRECISION)) as usize;
+        let rest = (x << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.set(index, rank);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        match &other.registers {
+            Registers::Sparse(pairs) => {
+                for &(index, rank) in pairs {
+                    self.set(index as usize, rank);
+                }
+            }
+            Registers::Dense(others) => {
+                let registers = self.densify();
+                for (register, other) in registers.iter_mut().zip(others) {
+                    *register = (*register).max(*other);
+                }
+            }
+        }
+    }
+
+    /// Estimated number of distinct hashes inserted
+    pub fn count(&self) -> u64 {
+        let registers = self.dense();
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small ranges are counted from the empty registers instead
+        let zeros = registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// Raises the register to `rank`
+    fn set(&mut self, index: usize, rank: u8) {
+        if let Registers::Sparse(pairs) = &mut self.registers {
+            match pairs.binary_search_by_key(&(index as u16), |&(i, _)| i) {
+                Ok(found) => pairs[found].1 = pairs[found].1.max(rank),
+                Err(at) => pairs.insert(at, (index as u16, rank)),
+            }
+            if pairs.len() <= SPARSE_MAX {
+                return;
+            }
+        }
+        let registers = self.densify();
+        registers[index] = registers[index].max(rank);
+    }
+
+    fn dense(&self) -> Cow<'_, [u8]> {
+        match &self.registers {
+            Registers::Sparse(pairs) => {
+                let mut registers = vec![0; REGISTERS];
+                for &(index, rank) in pairs {
+                    registers[index as usize] = rank;
+                }
+                Cow::Owned(registers)
+            }
+            Registers::Dense(registers) => Cow::Borrowed(registers),
+        }
+    }
+
+    fn densify(&mut self) -> &mut Vec<u8> {
+        if let Registers::Sparse(_) = self.registers {
+            self.registers = Registers::Dense(self.dense().into_owned());
+        }
+        match &mut self.registers {
+            Registers::Dense(registers) => registers,
+            Registers::Sparse(_) => unreachable!(),
+        }
+    }
+}
+
+/// SplitMix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HyperLogLog;
+
+    fn assert_close(expected: u64, actual: u64) {
+        let error = (actual as f64 - expected as f64).abs() / expected as f64;
+        assert!(error < 0.05, "expected ~{}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_count() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(0, hll.count());
+
+        for hash in 0..100 {
+            hll.insert(hash);
+            hll.insert(hash);
+        }
+        assert_close(100, hll.count());
+
+        for hash in 0..100_000 {
+            hll.insert(hash * 7919);
+        }
+        assert_close(100_000, hll.count());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = HyperLogLog::new();
+        let mut b = HyperLogLog::new();
+        for hash in 0..20_000 {
+            a.insert(hash);
+            b.insert(hash + 10_000);
+        }
+        a.merge(&b);
+        assert_close(30_000, a.count());
+
+        let stored = HyperLogLog::from_bytes(&a.to_bytes()).unwrap();
+        assert_eq!(a, stored);
+        assert_eq!(None, HyperLogLog::from_bytes(&[0; 10]));
+    }
+
+    #[test]
+    fn test_sparse() {
+        let mut sparse = HyperLogLog::new();
+        for hash in 0..3 {
+            sparse.insert(hash);
+        }
+        assert_eq!(9, sparse.to_bytes().len());
+        let stored = HyperLogLog::from_bytes(&sparse.to_bytes()).unwrap();
+        assert_eq!(sparse, stored);
+        assert_eq!(3, stored.count());
+        assert_eq!(None, HyperLogLog::from_bytes(&[0, 2, 1, 0, 1, 1]));
+
+        // Dense once that is smaller, the same registers either way
+        let mut dense = HyperLogLog::new();
+        for hash in 0..10_000 {
+            dense.insert(hash);
+        }
+        assert_eq!(4096, dense.to_bytes().len());
+        let mut merged = dense.clone();
+        merged.merge(&sparse);
+        sparse.merge(&dense);
+        assert_eq!(merged, sparse);
+        assert_close(10_000, sparse.count());
+    }
+}
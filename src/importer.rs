@@ -0,0 +1,1115 @@
+use crate::blocks::{self, Block, BlockReader, Bytes, LineBlocks, MappedBlocks};
+use crate::bots::{BotFilter, BotMode};
+use crate::db;
+use crate::filter::Filter;
+use crate::geoip::GeoIp;
+use crate::models::{LogEntry, User};
+use crate::parser::{parse_bytes, ParseError, Precision, Utf8Mode};
+use crate::store::{SqliteStore, Store};
+use crate::utils::ParallelSendErrorsAsExt;
+use clap::ValueEnum;
+use crossbeam_channel::{Receiver, SendError, Sender};
+use derive_more::From;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use self_cell::self_cell;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::thread;
+
+/// Input is parsed in blocks of about this many bytes, ~100k typical lines
+static CHUNK_BYTES: usize = 24 * 1024 * 1024;
+/// Parsed chunks waiting for the insert, see `Msg::ChunkQueued`
+pub static CHUNK_QUEUE: usize = 3;
+
+/// Progress of the import, one message per row and stage
+#[derive(From, Debug)]
+#[non_exhaustive]
+pub enum Msg {
+    LogParseError(ParseError),
+    LogFileIOError(io::Error),
+    DbError(db::DbError),
+    /// Bytes of a memory mapped input taken for parsing, a reader given to
+    /// `run_reader` can count them itself
+    #[from(ignore)]
+    BlockMapped(usize),
+    RowParsed,
+    RowFiltered,
+    RowUnique,
+    RowBot,
+    RowInserted,
+    /// Repeat of an inserted entry, added to its `hit_count` with
+    /// `Dedup::Count`
+    RowCounted,
+    /// Chunk sent to the insert, with the chunks now waiting and the last
+    /// timestamp of the chunk
+    #[from(ignore)]
+    ChunkQueued {
+        queued: usize,
+        timestamp: Option<i64>,
+    },
+    /// Chunk taken for the insert, with the chunks still waiting and its
+    /// last timestamp
+    #[from(ignore)]
+    ChunkDequeued {
+        queued: usize,
+        timestamp: Option<i64>,
+    },
+    AllParsingDone,
+    AllInsertDone,
+    SessionsUpdated(usize),
+}
+
+/// How entries with the same time, request and user are told apart
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dedup {
+    /// Repeats are duplicates, also of a double click or a retry
+    Strict,
+    /// Repeats on different lines are kept, so importing the same file again
+    /// adds nothing but overlapping files add their common lines again
+    Line,
+    /// Repeats add to the `hit_count` of the first one, also when the same
+    /// file is imported again
+    Count,
+}
+
+type Entries<'a> = Vec<LogEntry<'a>>;
+
+self_cell!(
+    /// Parsed entries borrowing the block they were parsed from, so that
+    /// strings are copied only for new dimensions in the insert
+    struct Chunk {
+        owner: Bytes,
+
+        #[covariant]
+        dependent: Entries,
+    }
+
+    impl {Debug}
+);
+
+#[derive(From, Debug)]
+enum ChunkMsg {
+    Lines(Chunk),
+    Bots(Vec<User<'static>>),
+}
+
+/// Chunk not sent because the insert has stopped on an error, which the
+/// import returns
+type Sent = Result<(), SendError<ChunkMsg>>;
+
+/// Error which stopped `Importer::run_file`
+#[derive(From, Debug)]
+pub enum ImportError {
+    /// The input couldn't be memory mapped
+    MapError(io::Error),
+    DbError(db::DbError),
+}
+
+/// Imports access log lines to the database
+///
+/// ```no_run
+/// use loggerson::{BotMode, Importer, Msg};
+/// use std::io::{BufRead, BufReader};
+///
+/// let file = std::fs::File::open("access_log").unwrap();
+/// let mut inserted = 0;
+/// Importer::new("access.db")
+///     .bot_mode(BotMode::Drop)
+///     .run(BufReader::new(file).lines(), |msg| {
+///         if let Msg::RowInserted = msg {
+///             inserted += 1;
+///         }
+///     })
+///     .unwrap();
+/// ```
+pub struct Importer<S = SqliteStore> {
+    store: S,
+    geoip: Option<GeoIp>,
+    bot_mode: BotMode,
+    bot_filter: BotFilter,
+    filters: Vec<Filter>,
+    utf8_mode: Utf8Mode,
+    precision: Precision,
+    dedup: Dedup,
+    dedup_window: i64,
+    session_gap: i64,
+    own_hosts: Vec<String>,
+    merge_lag: i64,
+    chunk_bytes: usize,
+}
+
+impl Importer<SqliteStore> {
+    /// Importer to the SQLite database file, created if missing. Panics if
+    /// the database can't be opened, see `open`.
+    pub fn new(db_path: impl Into<String>) -> Self {
+        Importer::open(&db_path.into()).expect("Unable to open the database")
+    }
+
+    /// Like `new`, but returns the error of opening the database
+    pub fn open(db_path: &str) -> db::Result<Self> {
+        Ok(Importer::with_store(SqliteStore::open(db_path)?))
+    }
+}
+
+impl<S: Store> Importer<S> {
+    /// Importer to other storage backend, e.g. `&mut MemoryStore` in tests
+    pub fn with_store(store: S) -> Self {
+        Importer {
+            store,
+            geoip: None,
+            bot_mode: BotMode::Mark,
+            bot_filter: BotFilter::new(),
+            filters: Vec::new(),
+            utf8_mode: Utf8Mode::Escape,
+            precision: Precision::Seconds,
+            dedup: Dedup::Strict,
+            dedup_window: 5 * 60,
+            session_gap: 30 * 60,
+            own_hosts: Vec::new(),
+            merge_lag: 60,
+            chunk_bytes: CHUNK_BYTES,
+        }
+    }
+
+    /// Country lookup, done before the IP is hashed
+    pub fn geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// What to do with the rows of users detected as bots, `Mark` by default
+    pub fn bot_mode(mut self, bot_mode: BotMode) -> Self {
+        self.bot_mode = bot_mode;
+        self
+    }
+
+    /// Bot rules, the built-in ones by default
+    pub fn bot_filter(mut self, bot_filter: BotFilter) -> Self {
+        self.bot_filter = bot_filter;
+        self
+    }
+
+    /// Imports only the rows matching all the filters
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// How to decode lines with invalid UTF-8, `Escape` by default
+    pub fn utf8_mode(mut self, utf8_mode: Utf8Mode) -> Self {
+        self.utf8_mode = utf8_mode;
+        self
+    }
+
+    /// Precision of the timestamps, `Seconds` by default. Repeated requests
+    /// of a user within it are dropped as duplicates.
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// How repeated requests of a user at the same time are stored,
+    /// `Strict` by default
+    pub fn dedup(mut self, dedup: Dedup) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Seconds before the latest entry within which duplicates are dropped
+    /// across the chunks with `Dedup::Strict`, 5 minutes by default. Zero
+    /// leaves them to the store.
+    pub fn dedup_window(mut self, seconds: i64) -> Self {
+        self.dedup_window = seconds;
+        self
+    }
+
+    /// Inactivity gap in seconds which starts a new session, 30 minutes by
+    /// default
+    pub fn session_gap(mut self, seconds: i64) -> Self {
+        self.session_gap = seconds;
+        self
+    }
+
+    /// Host of the site itself, referrers from it are flagged internal
+    pub fn own_host(mut self, host: impl Into<String>) -> Self {
+        self.own_hosts.push(host.into());
+        self
+    }
+
+    /// Seconds the entries of `run_merged` are held back from the time all
+    /// the inputs have reached, 1 minute by default. An entry logged later
+    /// than this out of order in its input is inserted out of order.
+    pub fn merge_lag(mut self, seconds: i64) -> Self {
+        self.merge_lag = seconds;
+        self
+    }
+
+    /// Size of the parsed blocks, smaller in tests to get many chunks
+    #[cfg(test)]
+    fn chunk_bytes(mut self, bytes: usize) -> Self {
+        self.chunk_bytes = bytes;
+        self
+    }
+
+    /// Imports the lines, `String`s or bytes without the line ending, calling
+    /// `progress` with every message on the calling thread. Returns after the
+    /// sessions are updated, or with the error which stopped the insert.
+    /// Errors of single rows are sent as messages instead.
+    ///
+    /// Import is made of three threads, with following data flow:
+    ///
+    /// * Parser -> SQL Insert
+    /// * Parser -> Calling thread
+    /// * SQL Insert -> Calling thread
+    ///
+    /// Additionally the Parser creates worker threads with Rayon. Each thread
+    /// should exit gracefully.
+    pub fn run<I, L>(self, lines: I, progress: impl FnMut(Msg)) -> db::Result<()>
+    where
+        I: IntoIterator<Item = io::Result<L>>,
+        I::IntoIter: Send,
+        L: AsRef<[u8]>,
+    {
+        let chunk_bytes = self.chunk_bytes;
+        self.run_blocks(LineBlocks::new(lines.into_iter(), chunk_bytes), progress)
+    }
+
+    /// Imports the lines read from `reader`, in large blocks without
+    /// splitting them to lines first
+    pub fn run_reader(self, reader: impl Read + Send, progress: impl FnMut(Msg)) -> db::Result<()> {
+        let chunk_bytes = self.chunk_bytes;
+        self.run_blocks(BlockReader::new(reader, chunk_bytes), progress)
+    }
+
+    /// Imports the lines of an uncompressed file, memory mapped instead of
+    /// read. Fails if the file can't be mapped, e.g. when it is a pipe.
+    ///
+    /// The file must not be truncated or rewritten during the import:
+    /// touching the mapped pages past a new end raises SIGBUS, which kills
+    /// the process. Log rotation with `copytruncate` does that, `run_reader`
+    /// is safe with it. Appending is fine, the lines after the mapped length
+    /// are not imported.
+    pub fn run_file(self, file: &File, progress: impl FnMut(Msg)) -> Result<(), ImportError> {
+        // Safety: not guaranteed, the map is valid only while no other
+        // process truncates or modifies the file, see above
+        let map = unsafe { Mmap::map(file)? };
+        let chunk_bytes = self.chunk_bytes;
+        self.run_blocks(MappedBlocks::new(map, chunk_bytes), progress)?;
+        Ok(())
+    }
+
+    /// Imports several inputs at once, e.g. the logs of load balanced
+    /// servers, merging their entries to time order. Parse errors have the
+    /// index of their input as `Location::source`.
+    pub fn run_merged<R: Read + Send>(
+        self,
+        readers: Vec<R>,
+        progress: impl FnMut(Msg),
+    ) -> db::Result<()> {
+        let sources = readers
+            .into_iter()
+            .map(|reader| BlockReader::new(reader, self.chunk_bytes))
+            .collect();
+        self.run_parser(
+            |parser, msg_sender, chunks_sender| {
+                parser.run_merged(msg_sender, chunks_sender, sources)
+            },
+            progress,
+        )
+    }
+
+    fn run_blocks(
+        self,
+        blocks: impl Iterator<Item = io::Result<Block>> + Send,
+        progress: impl FnMut(Msg),
+    ) -> db::Result<()> {
+        self.run_parser(
+            |parser, msg_sender, chunks_sender| parser.run(msg_sender, chunks_sender, blocks),
+            progress,
+        )
+    }
+
+    fn run_parser(
+        self,
+        parse: impl FnOnce(Parser, Sender<Msg>, Sender<ChunkMsg>) -> Sent + Send,
+        mut progress: impl FnMut(Msg),
+    ) -> db::Result<()> {
+        let Importer {
+            store,
+            geoip,
+            bot_mode,
+            bot_filter,
+            filters,
+            utf8_mode,
+            precision,
+            dedup,
+            dedup_window,
+            session_gap,
+            own_hosts,
+            merge_lag,
+            chunk_bytes: _,
+        } = self;
+        let parser = Parser {
+            geoip,
+            bot_mode,
+            bot_filter,
+            filters,
+            utf8_mode,
+            precision,
+            dedup,
+            recent: RecentKeys::new(dedup_window),
+            merge_lag,
+        };
+
+        let (chunks_sender, chunks_receiver) = crossbeam_channel::bounded::<ChunkMsg>(CHUNK_QUEUE);
+        let (msg_sender, msg_receiver) = crossbeam_channel::unbounded::<Msg>();
+
+        thread::scope(|scope| {
+            // Parser thread, stops early if the insert has failed
+            let msg_sender_for_parser = msg_sender.clone();
+            scope.spawn(move || parse(parser, msg_sender_for_parser, chunks_sender).ok());
+
+            // SQL Insert thread
+            let insert = scope.spawn(move || {
+                sql_insert_thread(
+                    msg_sender,
+                    chunks_receiver,
+                    store,
+                    dedup,
+                    session_gap,
+                    own_hosts,
+                )
+            });
+
+            // Ends when both threads have dropped their senders
+            for msg in msg_receiver {
+                progress(msg);
+            }
+            insert
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+}
+
+struct Parser {
+    geoip: Option<GeoIp>,
+    bot_mode: BotMode,
+    bot_filter: BotFilter,
+    filters: Vec<Filter>,
+    utf8_mode: Utf8Mode,
+    precision: Precision,
+    dedup: Dedup,
+    recent: RecentKeys,
+    /// Seconds held back from the time reached by all merged inputs
+    merge_lag: i64,
+}
+
+impl Parser {
+    fn run(
+        mut self,
+        msg_sender: Sender<Msg>,
+        chunks_sender: Sender<ChunkMsg>,
+        blocks: impl Iterator<Item = io::Result<Block>>,
+    ) -> Sent {
+        for block in blocks {
+            let Block { bytes, first_line } = match block {
+                Ok(block) => block,
+                Err(err) => {
+                    msg_sender.send(Msg::LogFileIOError(err)).unwrap();
+                    continue;
+                }
+            };
+            if let Bytes::Mapped(_, range) = &bytes {
+                msg_sender.send(Msg::BlockMapped(range.len())).unwrap();
+            }
+            let mut bots = Vec::new();
+            let chunk = Chunk::new(bytes, |bytes| {
+                let (entries, found) = self.parse_chunk(&msg_sender, bytes, first_line, 0);
+                bots = found.into_iter().map(User::into_owned).collect();
+                entries
+            });
+
+            send_chunk(&msg_sender, &chunks_sender, chunk)?;
+            if !bots.is_empty() {
+                chunks_sender.send(ChunkMsg::Bots(bots))?;
+            }
+        }
+        let bots = self.robots_txt_bots(&msg_sender);
+        if !bots.is_empty() {
+            chunks_sender.send(ChunkMsg::Bots(bots))?;
+        }
+        msg_sender.send(Msg::AllParsingDone).unwrap();
+        Ok(())
+    }
+
+    /// Like `run`, but reads the next block from the source which is furthest
+    /// behind and sends the entries up to `merge_lag` before the time all of
+    /// the sources have reached, as the lines of a source are only roughly in
+    /// time order. Entries are copied, as a chunk has entries from many
+    /// blocks.
+    fn run_merged(
+        mut self,
+        msg_sender: Sender<Msg>,
+        chunks_sender: Sender<ChunkMsg>,
+        sources: Vec<impl Iterator<Item = io::Result<Block>>>,
+    ) -> Sent {
+        let mut sources = sources
+            .into_iter()
+            .map(|blocks| MergeSource {
+                blocks,
+                pending: Vec::new(),
+                latest: i64::MIN,
+                done: false,
+            })
+            .collect::<Vec<_>>();
+        // Sent after all the entries, users of the pending ones aren't
+        // inserted yet
+        let mut bots = HashSet::new();
+
+        while let Some((index, source)) = sources
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, source)| !source.done)
+            .min_by_key(|(_, source)| source.latest)
+        {
+            match source.blocks.next() {
+                None => source.done = true,
+                Some(Err(err)) => msg_sender.send(Msg::LogFileIOError(err)).unwrap(),
+                Some(Ok(Block { bytes, first_line })) => {
+                    let (entries, found) = self.parse_chunk(&msg_sender, &bytes, first_line, index);
+                    bots.extend(found.into_iter().map(User::into_owned));
+                    if let Some(last) = entries.last() {
+                        source.latest = source.latest.max(last.timestamp);
+                    }
+                    source
+                        .pending
+                        .extend(entries.into_iter().map(LogEntry::into_owned));
+                    // Borders of the blocks may overlap a little
+                    source.pending.sort_by_key(|e| (e.timestamp, e.micros));
+                }
+            }
+
+            let reached = sources
+                .iter()
+                .filter(|source| !source.done)
+                .map(|source| source.latest.saturating_sub(self.merge_lag))
+                .min()
+                .unwrap_or(i64::MAX);
+            let mut entries = Vec::new();
+            for source in &mut sources {
+                let end = source.pending.partition_point(|e| e.timestamp <= reached);
+                entries.extend(source.pending.drain(..end));
+            }
+            if !entries.is_empty() {
+                entries.par_sort_by_key(|e| (e.timestamp, e.micros));
+                let chunk = Chunk::new(Bytes::Read(Vec::new()), |_| entries);
+                send_chunk(&msg_sender, &chunks_sender, chunk)?;
+            }
+        }
+
+        bots.extend(self.robots_txt_bots(&msg_sender));
+        if !bots.is_empty() {
+            chunks_sender.send(ChunkMsg::Bots(bots.into_iter().collect()))?;
+        }
+        msg_sender.send(Msg::AllParsingDone).unwrap();
+        Ok(())
+    }
+
+    /// Entries of the block sorted by time, and the users to mark as bots:
+    /// all the bots among them with `BotMode::Mark`, the ones with already
+    /// imported rows with `BotMode::Drop`
+    fn parse_chunk<'a>(
+        &mut self,
+        msg_sender: &Sender<Msg>,
+        bytes: &'a [u8],
+        first_line: usize,
+        source: usize,
+    ) -> (Vec<LogEntry<'a>>, HashSet<User<'a>>) {
+        // Split to lines in parallel too, in ranges cut after a line end
+        let ranges = blocks::split(bytes, rayon::current_num_threads())
+            .into_par_iter()
+            .map(blocks::lines)
+            .collect::<Vec<_>>();
+        let first_lines = ranges
+            .iter()
+            .scan(first_line, |next_line, lines| {
+                let first = *next_line;
+                *next_line += lines.len();
+                Some(first)
+            })
+            .collect::<Vec<_>>();
+
+        // Parse all rows in parallel
+        let parsed = ranges
+            .into_par_iter()
+            .zip(first_lines)
+            .flat_map_iter(|(lines, first)| {
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(index, line)| (first + index, line))
+            })
+            .map(|(number, line)| {
+                parse_bytes(line, self.utf8_mode, self.geoip.as_ref())
+                    .map(|mut e| {
+                        e.micros = self.precision.truncate(e.micros);
+                        if self.dedup == Dedup::Line {
+                            e.line_number = number as i64;
+                            e.source = source as i32;
+                        }
+                        e
+                    })
+                    .map_err(|err| err.at_line(number).in_source(source))
+            })
+            .send_errors_as(msg_sender, Msg::LogParseError)
+            .map(|e| {
+                msg_sender.send(Msg::RowParsed).unwrap();
+                e
+            })
+            .filter(|e| {
+                let included = self.filters.iter().all(|f| f.matches(e));
+                if !included {
+                    msg_sender.send(Msg::RowFiltered).unwrap();
+                }
+                included
+            })
+            .collect::<Vec<_>>();
+
+        // Repeats are dropped, or counted to the first one with
+        // `Dedup::Count`. Cloning the borrowed request doesn't copy the
+        // strings.
+        let mut firsts: HashMap<_, usize> = HashMap::new();
+        let mut entries: Vec<LogEntry> = Vec::with_capacity(parsed.len());
+        for e in parsed {
+            let key = (
+                e.timestamp,
+                e.micros,
+                e.line_number,
+                e.user.hash,
+                e.request.clone(),
+            );
+            match firsts.entry(key) {
+                Entry::Occupied(first) if self.dedup == Dedup::Count => {
+                    entries[*first.get()].hits += 1
+                }
+                Entry::Occupied(_) => {}
+                // Duplicate of an entry of the previous chunks
+                Entry::Vacant(_) if self.recent.contains(&e) => {}
+                Entry::Vacant(first) => {
+                    first.insert(entries.len());
+                    msg_sender.send(Msg::RowUnique).unwrap();
+                    entries.push(e);
+                }
+            }
+        }
+        if self.dedup == Dedup::Strict {
+            self.recent.insert(&entries);
+        }
+
+        // Sort by timestamp
+        entries.par_sort_by_key(|e| (e.timestamp, e.micros));
+
+        let bots = match self.bot_mode {
+            BotMode::Keep => Default::default(),
+            _ => self.bot_filter.find_bots(&entries),
+        };
+        entries
+            .iter()
+            .filter(|e| bots.contains(&e.user))
+            .for_each(|_| msg_sender.send(Msg::RowBot).unwrap());
+        let marked = match self.bot_mode {
+            BotMode::Keep => HashSet::new(),
+            BotMode::Mark => bots,
+            BotMode::Drop => {
+                entries.retain(|e| !bots.contains(&e.user));
+                self.bot_filter.take_late_bots().into_iter().collect()
+            }
+        };
+        (entries, marked)
+    }
+
+    /// Users which requested nothing but `/robots.txt` during the import, to
+    /// be marked after their rows are inserted
+    fn robots_txt_bots(&mut self, msg_sender: &Sender<Msg>) -> Vec<User<'static>> {
+        if self.bot_mode == BotMode::Keep {
+            return Vec::new();
+        }
+        let bots = self.bot_filter.take_robots_txt_only();
+        bots.values()
+            .for_each(|&count| (0..count).for_each(|_| msg_sender.send(Msg::RowBot).unwrap()));
+        bots.into_keys().collect()
+    }
+}
+
+/// Input of `Parser::run_merged` with its parsed entries not sent yet
+struct MergeSource<I> {
+    blocks: I,
+    /// Sorted by time
+    pending: Vec<LogEntry<'static>>,
+    /// Time of the last entry parsed
+    latest: i64,
+    done: bool,
+}
+
+fn send_chunk(msg_sender: &Sender<Msg>, chunks_sender: &Sender<ChunkMsg>, chunk: Chunk) -> Sent {
+    let timestamp = chunk.borrow_dependent().last().map(|e| e.timestamp);
+    chunks_sender.send(ChunkMsg::Lines(chunk))?;
+    msg_sender
+        .send(Msg::ChunkQueued {
+            queued: chunks_sender.len(),
+            timestamp,
+        })
+        .unwrap();
+    Ok(())
+}
+
+/// Hashed keys of the entries of the previous chunks, within `window`
+/// seconds of the latest one. Hashes don't borrow the chunks, a collision
+/// would drop an entry but is unlikely with the keys of a few minutes.
+struct RecentKeys {
+    window: i64,
+    keys: HashSet<u64>,
+    /// In the order inserted, which is about the time order of the input
+    by_time: VecDeque<(i64, u64)>,
+    latest: i64,
+}
+
+impl RecentKeys {
+    fn new(window: i64) -> Self {
+        RecentKeys {
+            window,
+            keys: HashSet::new(),
+            by_time: VecDeque::new(),
+            latest: i64::MIN,
+        }
+    }
+
+    fn hash(e: &LogEntry) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (e.timestamp, e.micros, e.user.hash, &e.request).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn contains(&self, e: &LogEntry) -> bool {
+        !self.keys.is_empty() && self.keys.contains(&Self::hash(e))
+    }
+
+    /// Adds the entries of a chunk and forgets the ones outside the window
+    fn insert(&mut self, entries: &[LogEntry]) {
+        if self.window <= 0 {
+            return;
+        }
+        for e in entries {
+            let key = Self::hash(e);
+            self.keys.insert(key);
+            self.by_time.push_back((e.timestamp, key));
+            self.latest = self.latest.max(e.timestamp);
+        }
+        while let Some(&(timestamp, key)) = self.by_time.front() {
+            if timestamp >= self.latest - self.window {
+                break;
+            }
+            self.keys.remove(&key);
+            self.by_time.pop_front();
+        }
+    }
+}
+
+fn sql_insert_thread(
+    msg_sender: Sender<Msg>,
+    chunks_receiver: Receiver<ChunkMsg>,
+    mut store: impl Store,
+    dedup: Dedup,
+    session_gap: i64,
+    own_hosts: Vec<String>,
+) -> db::Result<()> {
+    // Pre-populate caches
+    store.populate(&msg_sender)?;
+
+    for chunk_message in chunks_receiver.iter() {
+        match chunk_message {
+            ChunkMsg::Lines(chunk) => {
+                let entries = chunk.borrow_dependent();
+                msg_sender
+                    .send(Msg::ChunkDequeued {
+                        queued: chunks_receiver.len(),
+                        timestamp: entries.last().map(|e| e.timestamp),
+                    })
+                    .unwrap();
+                store.insert_batch(&msg_sender, entries, dedup)?
+            }
+            ChunkMsg::Bots(users) => store.mark_bots(&users)?,
+        }
+    }
+    msg_sender.send(Msg::AllInsertDone).unwrap();
+
+    let sessions = store.finish(&own_hosts, session_gap)?;
+    msg_sender.send(Msg::SessionsUpdated(sessions)).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dedup, Importer, Msg, RecentKeys};
+    use crate::bots::BotMode;
+    use crate::db::{self, init, DbError};
+    use crate::models::{LogEntry, User};
+    use crate::parser::{parse, Precision};
+    use crate::store::{MemoryStore, Store};
+    use crossbeam_channel::Sender;
+    use itertools::Itertools;
+    use std::io;
+
+    fn lines() -> Vec<io::Result<String>> {
+        [
+            r#"1.2.3.4 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "Mozilla/5.0""#,
+            r#"1.2.3.4 - - [10/Oct/2021:13:56:36 +0000] "GET /a HTTP/1.1" 200 10 "https://www.example.com/" "Mozilla/5.0""#,
+            "garbage",
+        ]
+        .iter()
+        .map(|line| Ok(line.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_import_lines() {
+        let path = std::env::temp_dir().join("loggerson_test_importer.db");
+        let _ = std::fs::remove_file(&path);
+        let db_path = path.to_str().unwrap().to_owned();
+
+        let (mut inserted, mut errors, mut sessions) = (0, 0, 0);
+        Importer::new(&db_path)
+            .run(lines(), |msg| match msg {
+                Msg::RowInserted => inserted += 1,
+                Msg::LogParseError(err) => {
+                    assert_eq!(Some(3), err.location().line_number);
+                    errors += 1
+                }
+                Msg::SessionsUpdated(count) => sessions = count,
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!((2, 1, 1), (inserted, errors, sessions));
+
+        let count: i64 = init(&db_path)
+            .unwrap()
+            .get()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM entrys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(2, count);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(format!("{}-wal", db_path));
+        let _ = std::fs::remove_file(format!("{}-shm", db_path));
+    }
+
+    /// Store which fails the insert after the first batch
+    struct FailingStore {
+        batches: usize,
+    }
+
+    impl Store for FailingStore {
+        fn populate(&mut self, _: &Sender<Msg>) -> db::Result<()> {
+            Ok(())
+        }
+
+        fn insert_batch(&mut self, _: &Sender<Msg>, _: &[LogEntry], _: Dedup) -> db::Result<()> {
+            self.batches += 1;
+            match self.batches {
+                1 => Ok(()),
+                _ => Err(rusqlite::Error::InvalidQuery.into()),
+            }
+        }
+
+        fn mark_bots(&mut self, _: &[User]) -> db::Result<()> {
+            Ok(())
+        }
+
+        fn finish(&mut self, _: &[String], _: i64) -> db::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_import_insert_error() {
+        let mut store = FailingStore { batches: 0 };
+        let lines = (0..100).map(|_| lines().remove(0));
+        let result = Importer::with_store(&mut store)
+            .chunk_bytes(1)
+            .run(lines, |_| {});
+        assert!(matches!(
+            result,
+            Err(DbError::SqliteError(rusqlite::Error::InvalidQuery))
+        ));
+        // The parser stops once the insert is gone
+        assert_eq!(2, store.batches);
+    }
+
+    #[test]
+    fn test_import_to_memory_store() {
+        let mut store = MemoryStore::new();
+        Importer::with_store(&mut store)
+            .run(lines(), |_| {})
+            .unwrap();
+
+        let mut duplicates = 0;
+        Importer::with_store(&mut store)
+            .own_host("example.com")
+            .run(lines(), |msg| {
+                if let Msg::DbError(DbError::DuplicateEntry) = msg {
+                    duplicates += 1;
+                }
+            })
+            .unwrap();
+        assert_eq!(2, duplicates);
+        assert_eq!(2, store.entries.len());
+        assert_eq!(1, store.sessions);
+        assert!(store.internal_hosts.contains("www.example.com"));
+    }
+
+    #[test]
+    fn test_import_precision() {
+        let lines = || {
+            ["36.100", "36.100", "36.150"].map(|time| {
+                Ok(format!(
+                    r#"1.2.3.4 - - [10/Oct/2021:13:55:{} +0000] "GET / HTTP/1.1" 200 10 "-" "-""#,
+                    time
+                ))
+            })
+        };
+        let mut store = MemoryStore::new();
+        Importer::with_store(&mut store)
+            .run(lines(), |_| {})
+            .unwrap();
+        assert_eq!(1, store.entries.len());
+
+        let mut store = MemoryStore::new();
+        Importer::with_store(&mut store)
+            .precision(Precision::Millis)
+            .run(lines(), |_| {})
+            .unwrap();
+        let micros = store.entries.iter().map(|e| e.micros).collect::<Vec<_>>();
+        assert_eq!(vec![100000, 150000], micros);
+    }
+
+    #[test]
+    fn test_import_dedup() {
+        let lines = || {
+            let line =
+                r#"1.2.3.4 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "-""#;
+            [Ok(line), Ok(line)]
+        };
+        let import = |dedup, store: &mut MemoryStore| {
+            let (mut duplicates, mut counted) = (0, 0);
+            Importer::with_store(store)
+                .dedup(dedup)
+                .run(lines(), |msg| match msg {
+                    Msg::DbError(DbError::DuplicateEntry) => duplicates += 1,
+                    Msg::RowCounted => counted += 1,
+                    _ => {}
+                })
+                .unwrap();
+            (duplicates, counted)
+        };
+
+        let mut store = MemoryStore::new();
+        import(Dedup::Strict, &mut store);
+        assert_eq!((1, 0), import(Dedup::Strict, &mut store));
+        assert_eq!(1, store.entries.len());
+
+        let mut store = MemoryStore::new();
+        import(Dedup::Line, &mut store);
+        assert_eq!((2, 0), import(Dedup::Line, &mut store));
+        let line_numbers = store.entries.iter().map(|e| e.line_number).collect_vec();
+        assert_eq!(vec![1, 2], line_numbers);
+
+        let mut store = MemoryStore::new();
+        import(Dedup::Count, &mut store);
+        assert_eq!((0, 1), import(Dedup::Count, &mut store));
+        assert_eq!(1, store.entries.len());
+        assert_eq!(4, store.entries[0].hits);
+    }
+
+    #[test]
+    fn test_import_bots_across_chunks() {
+        let line = |user: u8, url: &str| {
+            Ok(format!(
+                r#"1.2.3.{} - - [10/Oct/2021:13:55:{:02} +0000] "GET {} HTTP/1.1" 200 10 "-" "-""#,
+                user, user, url
+            ))
+        };
+        // One line per chunk: scanner first, robots.txt before browsing,
+        // nothing but robots.txt, and browsing before scanning
+        let lines = || {
+            vec![
+                line(1, "/wp-login.php"),
+                line(2, "/robots.txt"),
+                line(4, "/"),
+                line(1, "/"),
+                line(2, "/"),
+                line(3, "/robots.txt"),
+                line(4, "/.env"),
+            ]
+        };
+        let import = |bot_mode, store: &mut MemoryStore| {
+            let mut bot_rows = 0;
+            Importer::with_store(store)
+                .bot_mode(bot_mode)
+                .chunk_bytes(1)
+                .run(lines(), |msg| {
+                    if let Msg::RowBot = msg {
+                        bot_rows += 1;
+                    }
+                })
+                .unwrap();
+            bot_rows
+        };
+        let user = |octet| {
+            let line = line(octet, "/").unwrap();
+            parse(&line, None).unwrap().user.into_owned()
+        };
+
+        let mut store = MemoryStore::new();
+        assert_eq!(4, import(BotMode::Mark, &mut store));
+        assert!(store.bots.contains(&user(1)));
+        assert!(!store.bots.contains(&user(2)));
+        assert!(store.bots.contains(&user(3)));
+        assert!(store.bots.contains(&user(4)));
+
+        // The rows inserted before the detection are kept and marked, in the
+        // order of the input
+        let mut store = MemoryStore::new();
+        assert_eq!(4, import(BotMode::Drop, &mut store));
+        let rows = store
+            .entries
+            .iter()
+            .map(|e| (e.timestamp % 60, e.request.url.as_ref()))
+            .collect_vec();
+        assert_eq!(
+            vec![(2, "/robots.txt"), (4, "/"), (2, "/"), (3, "/robots.txt")],
+            rows
+        );
+        assert!(!store.bots.contains(&user(2)));
+        assert!(store.bots.contains(&user(3)));
+        assert!(store.bots.contains(&user(4)));
+    }
+
+    #[test]
+    fn test_recent_keys() {
+        let entry = |timestamp| {
+            let line = format!(
+                r#"1.2.3.4 - - [{}] "GET / HTTP/1.1" 200 10 "-" "-""#,
+                timestamp
+            );
+            parse(&line, None).unwrap().into_owned()
+        };
+        let first = entry("10/Oct/2021:13:55:00 +0000");
+        let mut recent = RecentKeys::new(60);
+        assert!(!recent.contains(&first));
+        recent.insert(std::slice::from_ref(&first));
+        assert!(recent.contains(&first));
+
+        // Still within the window, then forgotten
+        recent.insert(&[entry("10/Oct/2021:13:56:00 +0000")]);
+        assert!(recent.contains(&first));
+        recent.insert(&[entry("10/Oct/2021:13:56:01 +0000")]);
+        assert!(!recent.contains(&first));
+        assert_eq!(2, recent.by_time.len());
+
+        let mut disabled = RecentKeys::new(0);
+        disabled.insert(std::slice::from_ref(&first));
+        assert!(!disabled.contains(&first));
+    }
+
+    /// Lines of the same request at the seconds of a minute
+    fn log(seconds: &[u32]) -> String {
+        seconds
+            .iter()
+            .map(|second| {
+                format!(
+                    r#"1.2.3.4 - - [10/Oct/2021:13:55:{:02} +0000] "GET / HTTP/1.1" 200 10 "-" "-"{}"#,
+                    second, "\n"
+                )
+            })
+            .join("")
+    }
+
+    #[test]
+    fn test_import_merged() {
+        let first = log(&[1, 4, 5]) + "garbage\n";
+        let second = log(&[2, 3, 6]) + "garbage\n";
+
+        let mut store = MemoryStore::new();
+        let mut sources = Vec::new();
+        Importer::with_store(&mut store)
+            .run_merged(vec![first.as_bytes(), second.as_bytes()], |msg| {
+                if let Msg::LogParseError(err) = msg {
+                    sources.push((err.location().source, err.location().line_number));
+                }
+            })
+            .unwrap();
+        let timestamps = store.entries.iter().map(|e| e.timestamp % 60).collect_vec();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], timestamps);
+        sources.sort();
+        assert_eq!(vec![(0, Some(4)), (1, Some(4))], sources);
+
+        // The same line on the same line number of two servers is two hits
+        let mut store = MemoryStore::new();
+        Importer::with_store(&mut store)
+            .dedup(Dedup::Line)
+            .run_merged(vec![log(&[1]).as_bytes(), log(&[1]).as_bytes()], |_| {})
+            .unwrap();
+        let keys = store.entries.iter().map(|e| (e.source, e.line_number));
+        assert_eq!(vec![(0, 1), (1, 1)], keys.sorted().collect_vec());
+    }
+
+    #[test]
+    fn test_import_merged_lag() {
+        // Lines longer than the least read of `BlockReader`, so that each is
+        // a block. 20 is logged after 30 and read when the other input is at
+        // 35.
+        let url = format!("GET /?{} ", "a".repeat(70_000));
+        let padded = |seconds: &[u32]| log(seconds).replace("GET / ", &url);
+        let first = padded(&[10, 30, 20]);
+        let second = padded(&[15, 25, 35]);
+        let import = |lag| {
+            let mut store = MemoryStore::new();
+            Importer::with_store(&mut store)
+                .chunk_bytes(1)
+                .merge_lag(lag)
+                .run_merged(vec![first.as_bytes(), second.as_bytes()], |_| {})
+                .unwrap();
+            store.entries.iter().map(|e| e.timestamp % 60).collect_vec()
+        };
+        assert_eq!(vec![10, 15, 25, 30, 20, 35], import(0));
+        assert_eq!(vec![10, 15, 20, 25, 30, 35], import(10));
+    }
+
+    #[test]
+    fn test_import_mapped_file() {
+        let path = std::env::temp_dir().join("loggerson_test_importer_mapped.log");
+        let text = lines().into_iter().map(|line| line.unwrap()).join("\n");
+        std::fs::write(&path, text).unwrap();
+
+        let mut store = MemoryStore::new();
+        let (mut mapped, mut error_line) = (0, None);
+        let file = std::fs::File::open(&path).unwrap();
+        Importer::with_store(&mut store)
+            .run_file(&file, |msg| match msg {
+                Msg::BlockMapped(bytes) => mapped += bytes,
+                Msg::LogParseError(err) => error_line = err.location().line_number,
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!(2, store.entries.len());
+        assert!(store.entries[0].timestamp < store.entries[1].timestamp);
+        assert_eq!(Some(3), error_line);
+        assert_eq!(file.metadata().unwrap().len() as usize, mapped);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
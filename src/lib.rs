@@ -0,0 +1,29 @@
+//! Reads access logs to a small SQLite database
+//!
+//! [`Importer`] runs the whole import from any source of lines, [`parser`]
+//! and [`db`] are the lower level parts of it. Progress is reported as
+//! [`Msg`] values. Entries go to a [`store::Store`], SQLite by default.
+
+mod blocks;
+pub mod bots;
+pub mod db;
+pub mod export;
+pub mod filter;
+pub mod geoip;
+pub mod hll;
+pub mod importer;
+pub mod models;
+pub mod parser;
+pub mod quarantine;
+pub mod referrers;
+pub mod report;
+pub mod rollups;
+pub mod serve;
+pub mod sessions;
+pub mod sketches;
+pub mod store;
+pub mod useragent;
+mod utils;
+
+pub use bots::BotMode;
+pub use importer::{Dedup, ImportError, Importer, Msg};
@@ -1,211 +1,262 @@
-use crossbeam_channel::{Receiver, Sender};
-use derive_more::From;
-use itertools::Itertools;
-use rayon::prelude::*;
-use std::io::{BufRead, BufReader, Write};
-use std::thread;
-use std::time::Duration;
-use std::{fs::File, io, time::Instant};
-use utils::ParallelSendErrorsAsExt;
-
-use crate::db::batch_insert;
-use crate::db::{init, BatchCache};
-use crate::models::LogEntry;
-use crate::parser::parse;
-use crate::parser::ParseError;
-
-mod db;
-mod models;
-mod parser;
-mod utils;
-
-#[derive(From, Debug)]
-pub enum Msg {
-    LogParseError(ParseError),
-    LogFileIOError(io::Error),
-    DbError(db::DbError),
-    RowParsed,
-    RowUnique,
-    RowInserted,
-    AllParsingDone,
-    AllInsertDone,
-}
+use clap::{Args, Parser, Subcommand};
+use loggerson::bots::{BotFilter, BotMode};
+use loggerson::db::init;
+use loggerson::export::{self, ExportArgs};
+use loggerson::filter::Filter;
+use loggerson::geoip::GeoIp;
+use loggerson::parser::{Precision, Utf8Mode};
+use loggerson::quarantine::Quarantine;
+use loggerson::report::{self, ReportArgs};
+use loggerson::serve::{self, ServeArgs};
+#[cfg(feature = "postgres")]
+use loggerson::store::PostgresStore;
+use loggerson::store::Store;
+use loggerson::{Dedup, Importer, Msg};
+use progress::{DrawState, ProgressFormat};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::{fs::File, io};
 
-#[derive(From, Debug)]
-enum ChunkMsg {
-    Lines(Vec<LogEntry>),
-}
+mod progress;
+
+/// Reads access logs to a small sqlite database
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-#[derive(Debug)]
-struct DrawState {
-    parse_errors: usize,
-    parsed: usize,
-    unique: usize,
-    insert_errors: usize,
-    duplicates: usize,
-    insertted: usize,
-    drawed: Instant,
-    started: Instant,
-    ended: Option<Instant>,
-    // errors: Vec<Error>,
+    #[command(flatten)]
+    import: ImportArgs,
 }
 
-impl DrawState {
-    fn new() -> Self {
-        Self {
-            duplicates: 0,
-            insert_errors: 0,
-            insertted: 0,
-            unique: 0,
-            // last_errors: None,
-            parse_errors: 0,
-            parsed: 0,
-            started: Instant::now(),
-            drawed: Instant::now(),
-            ended: None,
-            // errors: Vec::new(),
-        }
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Imports an access log, this is the default without a command
+    Import(ImportArgs),
+    /// Prints a built-in report from the database
+    Report(ReportArgs),
+    /// Writes the entries joined with their dimensions as CSV, NDJSON or
+    /// Parquet
+    Export(ExportArgs),
+    /// Serves the reports as JSON and a dashboard page over HTTP, read-only
+    Serve(ServeArgs),
 }
 
-static CHUNK_SIZE: usize = 100000;
-static CHUNK_QUEUE: usize = 3;
-static TERMINAL_MS_PER_FRAME: u128 = 30; // Approx ~33 fps (1000 / 33 = 30ms per frame)
-
-/// This application is made of three threads, with following data flow:
-///
-/// * Parser -> SQL Insert
-/// * Parser -> Main msg thread
-/// * SQL Insert -> Main msg thread
-///
-/// Additionally the Parser creates worker threads with Rayon. Each thread
-/// should exit gracefully.
-fn main() {
-    let (chunks_sender, chunks_receiver) = crossbeam_channel::bounded::<ChunkMsg>(CHUNK_QUEUE);
-    let (msg_sender, msg_receiver) = crossbeam_channel::unbounded::<Msg>();
+#[derive(Args, Debug)]
+struct ImportArgs {
+    /// Access log files in combined log format. Several files, e.g. from
+    /// load balanced servers, are read at once and merged to time order.
+    #[arg(default_value = ".cache/access_log")]
+    inputs: Vec<PathBuf>,
 
-    // Parser thread
-    let msg_sender_for_parser = msg_sender.clone();
-    thread::spawn(move || parser_thread(msg_sender_for_parser, chunks_sender));
+    /// SQLite database file
+    #[arg(long, default_value = ".cache.db")]
+    db: String,
 
-    // SQL Insert thread
-    thread::spawn(move || sql_insert_thread(msg_sender, chunks_receiver));
+    /// Country lookup database, MaxMind `.mmdb` file or CSV range file with
+    /// `start_ip,end_ip,country_code` lines
+    #[arg(long)]
+    geoip: Option<PathBuf>,
 
-    msg_thread(msg_receiver)
-}
+    /// What to do with rows of users detected as bots
+    #[arg(long, value_enum, default_value_t = BotMode::Mark)]
+    bots: BotMode,
 
-fn parser_thread(msg_sender: Sender<Msg>, chunks_sender: Sender<ChunkMsg>) {
-    let file = File::open(".cache/access_log").unwrap();
-    let lines = BufReader::new(file).lines();
-    let line_chunks = lines.chunks(CHUNK_SIZE);
-
-    line_chunks.into_iter().for_each(|chunkedlines| -> () {
-        let lines = chunkedlines.collect_vec();
-
-        // Parse all rows in parallel
-        let mut entries = lines
-            .into_par_iter()
-            .send_errors_as(&msg_sender, Msg::LogFileIOError)
-            .map(parse)
-            .send_errors_as(&msg_sender, Msg::LogParseError)
-            .map(|e| {
-                msg_sender.send(Msg::RowParsed).unwrap();
-                e
-            })
-            .collect::<Vec<_>>()
-            .into_iter()
-            .unique_by(|e| (e.timestamp, e.user.hash, e.request.clone()))
-            .map(|e| {
-                msg_sender.send(Msg::RowUnique).unwrap();
-                e
-            })
-            .collect_vec();
+    /// Additional bot rules, one per line. Lines starting with `/` are
+    /// scanner paths, other lines are useragent words.
+    #[arg(long)]
+    bot_list: Option<PathBuf>,
 
-        // Sort by timestamp
-        entries.par_sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    /// Import only rows matching the expression, e.g. `status >= 200 &&
+    /// status < 400` or `url !~ "\.(css|js|png)$"`. Can be given multiple
+    /// times, all of them must match.
+    #[arg(long = "filter", value_parser = Filter::parse)]
+    filters: Vec<Filter>,
 
-        chunks_sender.send(ChunkMsg::Lines(entries)).unwrap();
-    });
-    msg_sender.send(Msg::AllParsingDone).unwrap();
+    /// How to decode lines which are not valid UTF-8
+    #[arg(long, value_enum, default_value_t = Utf8Mode::Escape)]
+    invalid_utf8: Utf8Mode,
+
+    /// Precision of the timestamps, repeated requests of a user within it
+    /// are dropped as duplicates
+    #[arg(long, value_enum, default_value_t = Precision::Seconds)]
+    precision: Precision,
+
+    /// How repeated requests of a user at the same time are stored: dropped,
+    /// kept if on different lines, or counted in `hit_count`
+    #[arg(long, value_enum, default_value_t = Dedup::Strict)]
+    dedup: Dedup,
+
+    /// Seconds before the latest entry within which duplicates are dropped
+    /// before the insert, also across the parsed chunks. 0 leaves them to the
+    /// database.
+    #[arg(long, default_value_t = 300)]
+    dedup_window: i64,
+
+    /// Seconds the merged entries of several inputs are held back, so that
+    /// lines logged out of order by up to this much are still inserted in
+    /// time order
+    #[arg(long, default_value_t = 60)]
+    merge_lag: i64,
+
+    /// Inactivity gap in minutes which starts a new session
+    #[arg(long, default_value_t = 30)]
+    session_gap: i64,
+
+    /// Host of the site itself, referrers from it and its subdomains are
+    /// flagged internal. Can be given multiple times.
+    #[arg(long = "own-host")]
+    own_hosts: Vec<String>,
+
+    /// Import to PostgreSQL instead of `--db`, e.g. `host=localhost
+    /// user=postgres dbname=logs`. Rollups, sketches and sessions are only
+    /// maintained in SQLite.
+    #[cfg(feature = "postgres")]
+    #[arg(long)]
+    postgres: Option<String>,
+
+    /// Memory map a regular input file instead of reading it. Truncating the
+    /// file during the import, e.g. by logrotate with `copytruncate`, kills
+    /// the import with SIGBUS.
+    #[arg(long)]
+    mmap: bool,
+
+    /// Write the lines which failed to parse to this file, tab separated
+    /// with the source file, line number and reason
+    #[arg(long)]
+    rejects: Option<PathBuf>,
+
+    /// How to show the progress and the final summary
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Auto)]
+    progress: ProgressFormat,
+
+    /// Shows no progress nor summary
+    #[arg(long, short)]
+    quiet: bool,
 }
 
-fn sql_insert_thread(msg_sender: Sender<Msg>, chunks_receiver: Receiver<ChunkMsg>) {
-    let conpool = init(".cache.db").unwrap();
-    let mut cache = BatchCache::new();
-
-    // Pre-populate caches
-    cache
-        .populate(&conpool.get().unwrap(), &msg_sender)
-        .unwrap();
-
-    for chunk_message in chunks_receiver {
-        match chunk_message {
-            ChunkMsg::Lines(entries) => {
-                let mut conn = conpool.get().unwrap();
-                let tx = conn.transaction().unwrap();
-                batch_insert(&msg_sender, &tx, &entries, &mut cache).unwrap();
-                tx.commit().unwrap();
-            }
-        }
+fn main() {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Import(cli.import)) {
+        Command::Import(args) => import(args),
+        Command::Report(args) => report(args),
+        Command::Export(args) => export(args),
+        Command::Serve(args) => serve::run(args),
     }
-    msg_sender.send(Msg::AllInsertDone).unwrap();
 }
 
-fn msg_thread(msg_receiver: Receiver<Msg>) {
-    let mut draw_state = DrawState::new();
+fn report(args: ReportArgs) {
+    let conpool = init(&args.db).unwrap();
+    let table = report::run(&conpool.get().unwrap(), &args).unwrap();
+    table.write(args.format, &mut io::stdout().lock()).unwrap();
+}
 
-    let mut send_draw = move || -> bool {
-        let now = Instant::now();
-        if (now - draw_state.drawed).as_millis() > TERMINAL_MS_PER_FRAME {
-            draw_state.drawed = now;
-            true
-        } else {
-            false
-        }
+fn export(args: ExportArgs) {
+    let conpool = init(&args.db).unwrap();
+    let mut out: Box<dyn Write + Send> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).unwrap())),
+        None => Box::new(BufWriter::new(io::stdout())),
     };
+    export::run(&conpool.get().unwrap(), &args, &mut out)
+        .unwrap_or_else(|err| panic!("Export failed: {}", err));
+}
 
-    loop {
-        use crossbeam_channel::RecvTimeoutError::*;
-        match msg_receiver.recv_timeout(Duration::from_millis(TERMINAL_MS_PER_FRAME as u64)) {
-            Ok(msg) => match msg {
-                Msg::RowInserted => draw_state.insertted += 1,
-                Msg::RowParsed => draw_state.parsed += 1,
-                Msg::RowUnique => draw_state.unique += 1,
-                Msg::AllParsingDone => {}
-                Msg::AllInsertDone => {}
-                Msg::LogFileIOError(_) => {}
-                Msg::LogParseError(_) => draw_state.parse_errors += 1,
-                Msg::DbError(err) => match err {
-                    db::DbError::SqliteError(_) => draw_state.insert_errors += 1,
-                    db::DbError::DuplicateEntry => draw_state.duplicates += 1,
-                },
-            },
-            Err(Disconnected) => break,
-            Err(Timeout) => (),
-        }
-
-        if send_draw() {
-            draw(&draw_state);
-        }
+fn import(args: ImportArgs) {
+    #[cfg(feature = "postgres")]
+    if let Some(params) = &args.postgres {
+        let store = PostgresStore::connect(params)
+            .unwrap_or_else(|err| panic!("Unable to connect to PostgreSQL: {:?}", err));
+        return run_import(Importer::with_store(store), args);
     }
-
-    draw_state.ended = Some(Instant::now());
-    draw(&draw_state);
+    let importer = Importer::open(&args.db)
+        .unwrap_or_else(|err| panic!("Unable to open the database: {:?}", err));
+    run_import(importer, args)
 }
 
-fn draw(state: &DrawState) {
-    print!(
-        "\rParsed {}, errors {}, unique ~{}. Inserted {}, duplicates {}, insert errors {}.",
-        state.parsed,
-        state.parse_errors,
-        state.unique,
-        state.insertted,
-        state.duplicates,
-        state.insert_errors
-    );
-    let _ = io::stdout().flush();
-    if let Some(ended) = state.ended {
-        println!("");
-        println!("Done in {} ms.", (ended - state.started).as_millis());
+fn run_import<S: Store>(importer: Importer<S>, args: ImportArgs) {
+    let mut importer = importer
+        .bot_mode(args.bots)
+        .utf8_mode(args.invalid_utf8)
+        .precision(args.precision)
+        .dedup(args.dedup)
+        .dedup_window(args.dedup_window)
+        .merge_lag(args.merge_lag)
+        .session_gap(args.session_gap * 60);
+    if let Some(path) = &args.geoip {
+        importer = importer.geoip(
+            GeoIp::open(path)
+                .unwrap_or_else(|err| panic!("Unable to open GeoIP database: {}", err)),
+        );
+    }
+    if let Some(path) = &args.bot_list {
+        let mut bot_filter = BotFilter::new();
+        bot_filter
+            .extend_from_file(path)
+            .unwrap_or_else(|err| panic!("Unable to read bot list: {}", err));
+        importer = importer.bot_filter(bot_filter);
+    }
+    for filter in args.filters {
+        importer = importer.filter(filter);
+    }
+    for host in args.own_hosts {
+        importer = importer.own_host(host);
+    }
+
+    let mut quarantine = args.rejects.as_ref().map(|path| {
+        let sources = args.inputs.iter().map(|input| input.display().to_string());
+        Quarantine::create(path, sources)
+            .unwrap_or_else(|err| panic!("Unable to create rejects file: {}", err))
+    });
+    let mut files = args
+        .inputs
+        .iter()
+        .map(|input| File::open(input).unwrap())
+        .collect::<Vec<_>>();
+    let mut draw_state = DrawState::new(args.progress, args.quiet);
+    let mut on_msg = |msg: Msg, draw_state: &mut DrawState| {
+        if let (Msg::LogParseError(err), Some(quarantine)) = (&msg, &mut quarantine) {
+            quarantine.write(err).unwrap();
+        }
+        draw_state.update(msg);
+        draw_state.tick();
+    };
+    if files.len() > 1 {
+        let total = files
+            .iter()
+            .map(|file| file.metadata().map(|m| m.len()).unwrap_or(0))
+            .sum();
+        let readers = files
+            .into_iter()
+            .map(|file| draw_state.input(file, total))
+            .collect();
+        importer
+            .run_merged(readers, |msg| on_msg(msg, &mut draw_state))
+            .unwrap_or_else(|err| panic!("Import failed: {:?}", err));
+    } else {
+        let file = files.pop().unwrap();
+        let metadata = file.metadata().unwrap();
+        // Regular files are memory mapped with `--mmap` and split without
+        // reading, others such as pipes are always read
+        if args.mmap && metadata.is_file() {
+            draw_state.mapped_input(metadata.len());
+            importer
+                .run_file(&file, |msg| on_msg(msg, &mut draw_state))
+                .unwrap_or_else(|err| panic!("Import failed: {:?}", err));
+        } else {
+            let total = if metadata.is_file() {
+                metadata.len()
+            } else {
+                0
+            };
+            let reader = draw_state.input(file, total);
+            importer
+                .run_reader(reader, |msg| on_msg(msg, &mut draw_state))
+                .unwrap_or_else(|err| panic!("Import failed: {:?}", err));
+        }
+    }
+    if let Some(quarantine) = &mut quarantine {
+        quarantine.flush().unwrap();
     }
+    draw_state.finish();
 }
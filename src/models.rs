@@ -1,31 +1,132 @@
+//! Parsed rows borrow their text from the read buffer, `into_owned` copies
+//! it e.g. when a dimension is cached
+
+use std::borrow::Cow;
+
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
-pub struct Request {
-    pub method: String,
-    pub url: String,
+pub struct Request<'a> {
+    pub method: Cow<'a, str>,
+    pub url: Cow<'a, str>,
     pub status_code: i32,
 }
 
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
-pub struct User {
+pub struct User<'a> {
     pub hash: Option<i64>,
-    pub useragent: Option<Useragent>,
-    // TODO: Country struct
+    pub useragent: Option<Useragent<'a>>,
+    pub country: Option<Country>,
+}
+
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+pub struct Country {
+    pub code: String,
 }
 
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
-pub struct Useragent {
-    pub value: String,
+pub struct Useragent<'a> {
+    pub value: Cow<'a, str>,
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
-pub struct LogEntry {
+pub struct LogEntry<'a> {
     pub timestamp: i64,
-    pub request: Request,
-    pub user: User,
-    pub referrer: Option<Referrer>,
+    /// Microseconds past `timestamp`, zero unless the import keeps the
+    /// sub-second precision
+    pub micros: i32,
+    /// Offset of the logged time from UTC in seconds, for local time reports
+    pub utc_offset: i32,
+    /// Line number in the input with `Dedup::Line`, 0 otherwise
+    pub line_number: i64,
+    /// Index of the input among the merged ones with `Dedup::Line`, 0
+    /// otherwise
+    pub source: i32,
+    /// Identical entries counted to this one with `Dedup::Count`, 1 otherwise
+    pub hits: i32,
+    pub request: Request<'a>,
+    pub user: User<'a>,
+    pub referrer: Option<Referrer<'a>>,
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
-pub struct Referrer {
-    pub url: String,
+pub struct Referrer<'a> {
+    pub url: Cow<'a, str>,
+}
+
+#[cfg(test)]
+impl LogEntry<'static> {
+    /// GET request of the url with status 200 by the user of the hash, with
+    /// no useragent, country or referrer, which tests set as needed
+    pub fn test(timestamp: i64, hash: i64, url: &str) -> Self {
+        LogEntry {
+            timestamp,
+            micros: 0,
+            utc_offset: 0,
+            line_number: 0,
+            source: 0,
+            hits: 1,
+            request: Request {
+                method: "GET".to_owned().into(),
+                url: url.to_owned().into(),
+                status_code: 200,
+            },
+            user: User {
+                hash: Some(hash),
+                useragent: None,
+                country: None,
+            },
+            referrer: None,
+        }
+    }
+}
+
+impl Request<'_> {
+    pub fn into_owned(self) -> Request<'static> {
+        Request {
+            method: Cow::Owned(self.method.into_owned()),
+            url: Cow::Owned(self.url.into_owned()),
+            status_code: self.status_code,
+        }
+    }
+}
+
+impl User<'_> {
+    pub fn into_owned(self) -> User<'static> {
+        User {
+            hash: self.hash,
+            useragent: self.useragent.map(Useragent::into_owned),
+            country: self.country,
+        }
+    }
+}
+
+impl Useragent<'_> {
+    pub fn into_owned(self) -> Useragent<'static> {
+        Useragent {
+            value: Cow::Owned(self.value.into_owned()),
+        }
+    }
+}
+
+impl LogEntry<'_> {
+    pub fn into_owned(self) -> LogEntry<'static> {
+        LogEntry {
+            timestamp: self.timestamp,
+            micros: self.micros,
+            utc_offset: self.utc_offset,
+            line_number: self.line_number,
+            source: self.source,
+            hits: self.hits,
+            request: self.request.into_owned(),
+            user: self.user.into_owned(),
+            referrer: self.referrer.map(Referrer::into_owned),
+        }
+    }
+}
+
+impl Referrer<'_> {
+    pub fn into_owned(self) -> Referrer<'static> {
+        Referrer {
+            url: Cow::Owned(self.url.into_owned()),
+        }
+    }
 }
@@ -1,28 +1,132 @@
+use crate::geoip::GeoIp;
 use crate::models::LogEntry;
 use crate::models::Referrer;
 use crate::models::Request;
 use crate::models::User;
 use crate::models::Useragent;
-use md5::compute as md5;
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::net::IpAddr;
-use std::str::FromStr;
+use std::io::Write;
+use std::net::{AddrParseError, IpAddr};
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::str::{FromStr, Utf8Error};
+
+/// Where parsing failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// Line number in the input starting from 1, set by the importer
+    pub line_number: Option<usize>,
+    /// Index of the input when importing several at once, otherwise 0
+    pub source: usize,
+    /// Byte range of the offending part, the whole line if nothing matched
+    pub span: Range<usize>,
+    pub line: String,
+}
+
+impl Location {
+    fn new(line: &str, span: Range<usize>) -> Self {
+        Location {
+            line_number: None,
+            source: 0,
+            span,
+            line: line.to_owned(),
+        }
+    }
+
+    /// The offending part of the line
+    pub fn substring(&self) -> &str {
+        self.line.get(self.span.clone()).unwrap_or_default()
+    }
+}
 
 #[derive(Debug)]
-pub struct ParseError(String);
+#[non_exhaustive]
+pub enum ParseError {
+    /// Not a combined log format line
+    NoMatch(Location),
+    InvalidIp(Location, AddrParseError),
+    InvalidTimestamp(Location, chrono::ParseError),
+    InvalidStatus(Location, ParseIntError),
+    /// Span is the invalid bytes, the line is decoded lossily
+    InvalidUtf8(Location, Utf8Error),
+}
 
 impl ParseError {
-    pub fn new(line: impl AsRef<str>) -> Self {
-        ParseError(line.as_ref().to_owned())
+    pub fn location(&self) -> &Location {
+        match self {
+            ParseError::NoMatch(location)
+            | ParseError::InvalidIp(location, _)
+            | ParseError::InvalidTimestamp(location, _)
+            | ParseError::InvalidStatus(location, _)
+            | ParseError::InvalidUtf8(location, _) => location,
+        }
+    }
+
+    /// Short description of the variant, for grouping
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::NoMatch(_) => "no match",
+            ParseError::InvalidIp(_, _) => "invalid ip",
+            ParseError::InvalidTimestamp(_, _) => "invalid timestamp",
+            ParseError::InvalidStatus(_, _) => "invalid status",
+            ParseError::InvalidUtf8(_, _) => "invalid utf-8",
+        }
+    }
+
+    fn location_mut(&mut self) -> &mut Location {
+        match self {
+            ParseError::NoMatch(location)
+            | ParseError::InvalidIp(location, _)
+            | ParseError::InvalidTimestamp(location, _)
+            | ParseError::InvalidStatus(location, _)
+            | ParseError::InvalidUtf8(location, _) => location,
+        }
+    }
+
+    pub fn at_line(mut self, line_number: usize) -> Self {
+        self.location_mut().line_number = Some(line_number);
+        self
+    }
+
+    pub fn in_source(mut self, source: usize) -> Self {
+        self.location_mut().source = source;
+        self
     }
 }
 
-impl std::error::Error for ParseError {}
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::NoMatch(_) => None,
+            ParseError::InvalidIp(_, err) => Some(err),
+            ParseError::InvalidTimestamp(_, err) => Some(err),
+            ParseError::InvalidStatus(_, err) => Some(err),
+            ParseError::InvalidUtf8(_, err) => Some(err),
+        }
+    }
+}
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Unable to parse line '{}'", self.0)
+        let location = self.location();
+        match location.line_number {
+            Some(number) => write!(f, "Unable to parse line {}", number)?,
+            None => write!(f, "Unable to parse line")?,
+        }
+        match self {
+            ParseError::NoMatch(_) => write!(f, ", no match: '{}'", location.line),
+            _ => write!(
+                f,
+                ", {} '{}' at {}: '{}'",
+                self.kind(),
+                location.substring(),
+                location.span.start,
+                location.line
+            ),
+        }
     }
 }
 
@@ -34,8 +138,106 @@ static COMBINED_LOG_REGEX: Lazy<Regex> = Lazy::new(|| {
     ).unwrap()
 });
 
-pub fn parse(line: String) -> Result<LogEntry, ParseError> {
-    if let Some(captures) = COMBINED_LOG_REGEX.captures(&line) {
+/// Precision of the timestamps kept by the import. Entries within the
+/// precision are duplicates if the request and user are the same.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Precision {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+impl Precision {
+    /// Microseconds past the second truncated to the precision
+    pub fn truncate(self, micros: i32) -> i32 {
+        match self {
+            Precision::Seconds => 0,
+            Precision::Millis => micros - micros % 1000,
+            Precision::Micros => micros,
+        }
+    }
+}
+
+/// How to decode lines which are not valid UTF-8, scanners send raw bytes in
+/// URLs and useragents
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Utf8Mode {
+    /// Invalid bytes as `%XX`, like in URLs
+    Escape,
+    /// Invalid bytes as the replacement character
+    Lossy,
+    /// Line is a `ParseError::InvalidUtf8`
+    Reject,
+}
+
+/// Parses a line read as bytes, without the line ending. Entry borrows the
+/// line, unless it had to be decoded.
+pub fn parse_bytes<'a>(
+    line: &'a [u8],
+    utf8_mode: Utf8Mode,
+    geoip: Option<&GeoIp>,
+) -> Result<LogEntry<'a>, ParseError> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    match (std::str::from_utf8(line), utf8_mode) {
+        (Ok(line), _) => parse(line, geoip),
+        (Err(_), Utf8Mode::Escape) => {
+            parse(&escape_invalid_utf8(line), geoip).map(LogEntry::into_owned)
+        }
+        (Err(_), Utf8Mode::Lossy) => {
+            parse(&String::from_utf8_lossy(line), geoip).map(LogEntry::into_owned)
+        }
+        // Span is the first replacement character in the lossily decoded line
+        (Err(utf8_error), Utf8Mode::Reject) => {
+            let start = utf8_error.valid_up_to();
+            let line = String::from_utf8_lossy(line);
+            let span = start..start + char::REPLACEMENT_CHARACTER.len_utf8();
+            Err(ParseError::InvalidUtf8(
+                Location::new(&line, span),
+                utf8_error,
+            ))
+        }
+    }
+}
+
+fn escape_invalid_utf8(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len() + 16);
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+/// Parses the time between the brackets: `10/Oct/2021:13:55:36 +0000`
+/// optionally with a fraction after the seconds, RFC 3339 like
+/// `2021-10-10T13:55:36.123+00:00`, or the time since the epoch as logged by
+/// `%{sec}t`, `%{msec}t` or `%{usec}t`. The unit is told by the number of
+/// digits, 10, 13 or 16 for the years 2001 to 2286, others are errors.
+fn parse_time(text: &str) -> Result<DateTime<FixedOffset>, chrono::ParseError> {
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        let time = match text.len() {
+            10 => text
+                .parse()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            13 => text.parse().ok().and_then(DateTime::from_timestamp_millis),
+            16 => text.parse().ok().and_then(DateTime::from_timestamp_micros),
+            _ => None,
+        };
+        if let Some(time) = time {
+            return Ok(time.fixed_offset());
+        }
+    }
+    DateTime::parse_from_str(text, "%d/%b/%Y:%H:%M:%S%.f %z")
+        .or_else(|err| DateTime::parse_from_rfc3339(text).map_err(|_| err))
+}
+
+/// Parses a combined log format line, if `geoip` is given the country is
+/// looked up before the IP is hashed away
+pub fn parse<'a>(line: &'a str, geoip: Option<&GeoIp>) -> Result<LogEntry<'a>, ParseError> {
+    if let Some(captures) = COMBINED_LOG_REGEX.captures(line) {
         if let (
             Some(ipmatch),
             Some(datematch),
@@ -57,37 +259,46 @@ pub fn parse(line: String) -> Result<LogEntry, ParseError> {
             captures.name("referrer"),
             captures.name("useragent"),
         ) {
-            let ip = IpAddr::from_str(ipmatch.as_str()).map_err(|_| ParseError::new(&line))?;
-            let dtime =
-                chrono::DateTime::parse_from_str(datematch.as_str(), "%d/%b/%Y:%H:%M:%S %z")
-                    .map_err(|_| ParseError::new(&line))?;
-            let method = methodmatch.as_str().to_owned();
-            let url = urlmatch.as_str().to_owned();
+            let ip = IpAddr::from_str(ipmatch.as_str())
+                .map_err(|err| ParseError::InvalidIp(Location::new(line, ipmatch.range()), err))?;
+            let dtime = parse_time(datematch.as_str()).map_err(|err| {
+                ParseError::InvalidTimestamp(Location::new(line, datematch.range()), err)
+            })?;
+            let method = methodmatch.as_str().into();
+            let url = urlmatch.as_str().into();
             let useragent = (useragentmatch.as_str() != "-").then(|| Useragent {
-                value: useragentmatch.as_str().to_owned(),
+                value: useragentmatch.as_str().into(),
             });
             let referrer = (referrermatch.as_str() != "-").then(|| Referrer {
-                url: referrermatch.as_str().to_owned(),
+                url: referrermatch.as_str().into(),
             });
-            let status_code = statusmatch
-                .as_str()
-                .parse::<i32>()
-                .map_err(|_| ParseError::new(&line))?;
+            let status_code = statusmatch.as_str().parse::<i32>().map_err(|err| {
+                ParseError::InvalidStatus(Location::new(line, statusmatch.range()), err)
+            })?;
+            let country = geoip.and_then(|geoip| geoip.lookup(ip));
 
             // Truncate hash, and use bad hasher (which has known collisions
             // like md5), to make pin-pointing a user somewhat difficult. Since
             // the hash includes useragent there can be inifinite amount of
             // collisions if useragent list is cleaned periodically.
-            let hash_bytes: [u8; 16] = md5(ip.to_string() + useragentmatch.as_str()).into();
+            let mut context = md5::Context::new();
+            write!(context, "{}{}", ip, useragentmatch.as_str()).unwrap();
+            let hash_bytes: [u8; 16] = context.compute().into();
             let mut hash_64b: [u8; 8] = [0; 8];
             hash_64b.copy_from_slice(&hash_bytes[0..8]);
             let hash = i64::from_le_bytes(hash_64b);
 
             Ok(LogEntry {
                 timestamp: dtime.timestamp(),
+                micros: dtime.timestamp_subsec_micros() as i32,
+                utc_offset: dtime.offset().local_minus_utc(),
+                line_number: 0,
+                source: 0,
+                hits: 1,
                 user: User {
                     hash: Some(hash),
                     useragent,
+                    country,
                 },
                 request: Request {
                     method,
@@ -98,10 +309,108 @@ pub fn parse(line: String) -> Result<LogEntry, ParseError> {
             })
         } else {
             // println!("Parsing row failed 1 {}", &line);
-            Err(ParseError::new(line))
+            Err(ParseError::NoMatch(Location::new(line, 0..line.len())))
         }
     } else {
         // println!("Parsing row failed 2 {}", &line);
-        Err(ParseError::new(line))
+        Err(ParseError::NoMatch(Location::new(line, 0..line.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_bytes, parse_time, ParseError, Precision, Utf8Mode};
+
+    #[test]
+    fn test_parse_errors() {
+        let line = |ip: &str, date: &str, status: &str| {
+            format!(
+                r#"{} - - [{}] "GET / HTTP/1.1" {} 10 "-" "Mozilla/5.0""#,
+                ip, date, status
+            )
+        };
+        let ok = line("1.2.3.4", "10/Oct/2021:13:55:36 +0000", "200");
+        assert_eq!(1633874136, parse(&ok, None).unwrap().timestamp);
+        let ok = line("1.2.3.4", "10/Oct/2021:15:55:36 +0200", "200");
+        let entry = parse(&ok, None).unwrap();
+        assert_eq!((1633874136, 7200), (entry.timestamp, entry.utc_offset));
+
+        let err = parse("garbage", None).unwrap_err();
+        assert!(matches!(err, ParseError::NoMatch(_)));
+        assert_eq!("garbage", err.location().substring());
+
+        let err = parse(&line("1.2.3", "10/Oct/2021:13:55:36 +0000", "200"), None).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidIp(_, _)));
+        assert_eq!("1.2.3", err.location().substring());
+        assert_eq!(0..5, err.location().span);
+
+        let err = parse(&line("1.2.3.4", "10/Foo/2021:13:55:36 +0000", "200"), None)
+            .unwrap_err()
+            .at_line(7);
+        assert!(matches!(err, ParseError::InvalidTimestamp(_, _)));
+        assert_eq!("10/Foo/2021:13:55:36 +0000", err.location().substring());
+        assert!(err.to_string().starts_with(
+            "Unable to parse line 7, invalid timestamp '10/Foo/2021:13:55:36 +0000' at 13:"
+        ));
+
+        let err = parse(
+            &line("1.2.3.4", "10/Oct/2021:13:55:36 +0000", "99999999999"),
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::InvalidStatus(_, _)));
+        assert_eq!("99999999999", err.location().substring());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let time = parse_time("10/Oct/2021:15:55:36.123456 +0200").unwrap();
+        assert_eq!(1633874136, time.timestamp());
+        assert_eq!(123456, time.timestamp_subsec_micros());
+        let time = parse_time("2021-10-10T13:55:36.5Z").unwrap();
+        assert_eq!(
+            (1633874136, 500000),
+            (time.timestamp(), time.timestamp_subsec_micros())
+        );
+        let epoch = |text| {
+            let time = parse_time(text).unwrap();
+            (time.timestamp(), time.timestamp_subsec_micros())
+        };
+        assert_eq!((1633874136, 0), epoch("1633874136"));
+        assert_eq!((1633874136, 123000), epoch("1633874136123"));
+        assert_eq!((1633874136, 123456), epoch("1633874136123456"));
+        // Not a unit of a time since 2001, e.g. a truncated line
+        assert!(parse_time("163387413").is_err());
+        assert!(parse_time("16338741361").is_err());
+        assert!(parse_time("").is_err());
+
+        assert_eq!(0, Precision::Seconds.truncate(123456));
+        assert_eq!(123000, Precision::Millis.truncate(123456));
+        assert_eq!(123456, Precision::Micros.truncate(123456));
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        let line = br#"1.2.3.4 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "-""#;
+        let mut crlf = line.to_vec();
+        crlf.extend_from_slice(b"\r");
+        assert!(parse_bytes(&crlf, Utf8Mode::Reject, None).is_ok());
+
+        let mut invalid = line.to_vec();
+        invalid.splice(47..47, [0xff]);
+        let err = parse_bytes(&invalid, Utf8Mode::Reject, None).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidUtf8(_, _)));
+        assert_eq!("\u{fffd}", err.location().substring());
+        assert_eq!(47, err.location().span.start);
+
+        // In the URL, and an incomplete sequence in the useragent
+        invalid.splice(71..71, [0xe2, 0x82]);
+        let entry = parse_bytes(&invalid, Utf8Mode::Escape, None).unwrap();
+        assert_eq!("GET", entry.request.method);
+        assert_eq!("/%FF", entry.request.url);
+        assert_eq!("%E2%82-", entry.user.useragent.unwrap().value);
+        let entry = parse_bytes(&invalid, Utf8Mode::Lossy, None).unwrap();
+        assert_eq!("/\u{fffd}", entry.request.url);
+        assert_eq!("\u{fffd}-", entry.user.useragent.unwrap().value);
     }
 }
@@ -0,0 +1,428 @@
+//! Import progress for the command line, either redrawn on the terminal or
+//! written as JSON lines for logs
+
+use chrono::DateTime;
+use clap::ValueEnum;
+use loggerson::importer::CHUNK_QUEUE;
+use loggerson::{db, Msg};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+static TERMINAL_MS_PER_FRAME: u128 = 30; // Approx ~33 fps (1000 / 33 = 30ms per frame)
+static JSON_MS_PER_EVENT: u128 = 1000;
+/// Rows per second are measured over this long
+static RATE_MS: u128 = 1000;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgressFormat {
+    /// Terminal display when stdout is a terminal, JSON otherwise
+    Auto,
+    /// Throughput, ETA and counters redrawn on a few lines
+    Tty,
+    /// One JSON object per line, `"event": "progress"` every second and
+    /// `"event": "summary"` at the end
+    Json,
+}
+
+impl ProgressFormat {
+    fn resolve(self) -> Self {
+        match self {
+            ProgressFormat::Auto if io::stdout().is_terminal() => ProgressFormat::Tty,
+            ProgressFormat::Auto => ProgressFormat::Json,
+            format => format,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DrawState {
+    parse_errors: usize,
+    /// Parse errors by `ParseError::kind`
+    parse_error_kinds: BTreeMap<&'static str, usize>,
+    parsed: usize,
+    filtered: usize,
+    unique: usize,
+    bots: usize,
+    insert_errors: usize,
+    duplicates: usize,
+    insertted: usize,
+    sessions: usize,
+    drawed: Instant,
+    started: Instant,
+    ended: Option<Instant>,
+    /// `None` when quiet
+    format: Option<ProgressFormat>,
+    /// Read so far, shared with `CountingReader` or counted from the mapped
+    /// blocks, and total input size
+    bytes_read: Arc<AtomicU64>,
+    bytes_total: u64,
+    /// Chunks waiting for the insert
+    queued: usize,
+    /// Log timestamps of the last parsed and inserted chunks
+    parsed_timestamp: Option<i64>,
+    inserted_timestamp: Option<i64>,
+    parse_rate: Rate,
+    insert_rate: Rate,
+    /// Lines of the previous terminal frame, to be overwritten
+    lines_drawn: usize,
+    // errors: Vec<Error>,
+}
+
+impl DrawState {
+    pub fn new(format: ProgressFormat, quiet: bool) -> Self {
+        Self {
+            duplicates: 0,
+            insert_errors: 0,
+            insertted: 0,
+            sessions: 0,
+            unique: 0,
+            bots: 0,
+            // last_errors: None,
+            parse_errors: 0,
+            parse_error_kinds: BTreeMap::new(),
+            parsed: 0,
+            filtered: 0,
+            started: Instant::now(),
+            drawed: Instant::now(),
+            ended: None,
+            format: (!quiet).then(|| format.resolve()),
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            bytes_total: 0,
+            queued: 0,
+            parsed_timestamp: None,
+            inserted_timestamp: None,
+            parse_rate: Rate::new(),
+            insert_rate: Rate::new(),
+            lines_drawn: 0,
+            // errors: Vec::new(),
+        }
+    }
+
+    /// Reader counting the bytes read of input of `total` bytes, the readers
+    /// of several inputs share the count
+    pub fn input<R: Read>(&mut self, reader: R, total: u64) -> CountingReader<R> {
+        self.bytes_total = total;
+        CountingReader {
+            inner: reader,
+            count: self.bytes_read.clone(),
+        }
+    }
+
+    /// Input of `total` bytes memory mapped by the importer, counted from
+    /// `Msg::BlockMapped`
+    pub fn mapped_input(&mut self, total: u64) {
+        self.bytes_total = total;
+    }
+
+    pub fn update(&mut self, msg: Msg) {
+        match msg {
+            Msg::RowInserted => self.insertted += 1,
+            // Repeat of an existing entry, only its hit count is updated
+            Msg::RowCounted => self.duplicates += 1,
+            Msg::RowParsed => self.parsed += 1,
+            Msg::RowFiltered => self.filtered += 1,
+            Msg::RowUnique => self.unique += 1,
+            Msg::RowBot => self.bots += 1,
+            Msg::ChunkQueued { queued, timestamp } => {
+                self.queued = queued;
+                self.parsed_timestamp = timestamp.or(self.parsed_timestamp);
+            }
+            Msg::ChunkDequeued { queued, timestamp } => {
+                self.queued = queued;
+                self.inserted_timestamp = timestamp.or(self.inserted_timestamp);
+            }
+            Msg::BlockMapped(bytes) => {
+                self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            Msg::AllParsingDone => {}
+            Msg::AllInsertDone => {}
+            Msg::SessionsUpdated(sessions) => self.sessions = sessions,
+            Msg::LogFileIOError(_) => {}
+            Msg::LogParseError(err) => {
+                self.parse_errors += 1;
+                *self.parse_error_kinds.entry(err.kind()).or_default() += 1;
+            }
+            Msg::DbError(db::DbError::DuplicateEntry) => self.duplicates += 1,
+            Msg::DbError(_) => self.insert_errors += 1,
+            _ => {}
+        }
+    }
+
+    /// Draws if enough time has passed since the previous frame
+    pub fn tick(&mut self) {
+        let interval = match self.format {
+            Some(ProgressFormat::Json) => JSON_MS_PER_EVENT,
+            Some(_) => TERMINAL_MS_PER_FRAME,
+            None => return,
+        };
+        let now = Instant::now();
+        if (now - self.drawed).as_millis() > interval {
+            self.drawed = now;
+            self.draw();
+        }
+    }
+
+    /// Draws the final state with the summary
+    pub fn finish(&mut self) {
+        self.ended = Some(Instant::now());
+        self.draw();
+    }
+
+    fn draw(&mut self) {
+        match self.format {
+            Some(ProgressFormat::Json) => {
+                let event = self.to_json();
+                let mut stdout = io::stdout().lock();
+                let _ = writeln!(stdout, "{}", event);
+                let _ = stdout.flush();
+            }
+            Some(_) => {
+                let now = Instant::now();
+                self.parse_rate.update(now, self.parsed);
+                self.insert_rate
+                    .update(now, self.insertted + self.duplicates + self.insert_errors);
+                let frame = self.to_lines();
+                let mut stdout = io::stdout().lock();
+                // Back to the first line of the previous frame
+                if self.lines_drawn > 0 {
+                    let _ = write!(stdout, "\x1b[{}A", self.lines_drawn);
+                }
+                for line in &frame {
+                    let _ = writeln!(stdout, "\r\x1b[2K{}", line);
+                }
+                let _ = stdout.flush();
+                self.lines_drawn = frame.len();
+            }
+            None => {}
+        }
+    }
+
+    /// Terminal frame
+    fn to_lines(&self) -> Vec<String> {
+        let now = self.ended.unwrap_or_else(Instant::now);
+        let elapsed = (now - self.started).as_secs_f64();
+        let bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        let percent = match self.bytes_total {
+            0 => 100.0,
+            total => 100.0 * bytes_read as f64 / total as f64,
+        };
+        // Assumes the rest of the input is read as fast as so far
+        let eta = match (self.ended, bytes_read) {
+            (Some(_), _) => format!("done in {}", format_duration(elapsed as u64)),
+            (None, 0) => "ETA -".to_owned(),
+            (None, read) => {
+                let remaining = self.bytes_total.saturating_sub(read) as f64;
+                format!(
+                    "ETA {}",
+                    format_duration((elapsed * remaining / read as f64) as u64)
+                )
+            }
+        };
+        // Averages over the whole import at the end
+        let (parse_per_sec, insert_per_sec) = match self.ended {
+            Some(_) => (
+                self.parsed as f64 / elapsed,
+                (self.insertted + self.duplicates + self.insert_errors) as f64 / elapsed,
+            ),
+            None => (self.parse_rate.per_sec, self.insert_rate.per_sec),
+        };
+        let queue = (0..CHUNK_QUEUE)
+            .map(|i| if i < self.queued { '#' } else { '-' })
+            .collect::<String>();
+
+        let mut lines = vec![
+            format!(
+                "Read     {} / {} ({:.0}%), {}",
+                format_bytes(bytes_read),
+                format_bytes(self.bytes_total),
+                percent,
+                eta
+            ),
+            format!(
+                "Parse    {:>8.0} rows/s. Parsed {}, errors {}, filtered {}, unique ~{}, bots {}.",
+                parse_per_sec,
+                self.parsed,
+                self.parse_errors,
+                self.filtered,
+                self.unique,
+                self.bots
+            ),
+            format!(
+                "Insert   {:>8.0} rows/s. Inserted {}, duplicates {}, insert errors {}.",
+                insert_per_sec, self.insertted, self.duplicates, self.insert_errors
+            ),
+            format!(
+                "Queue    [{}] {}/{} chunks waiting for insert",
+                queue, self.queued, CHUNK_QUEUE
+            ),
+            format!(
+                "Log time parsed {}, inserted {}",
+                format_timestamp(self.parsed_timestamp),
+                format_timestamp(self.inserted_timestamp)
+            ),
+        ];
+        if !self.parse_error_kinds.is_empty() {
+            let kinds = self
+                .parse_error_kinds
+                .iter()
+                .map(|(kind, count)| format!("{} {}", kind, count))
+                .collect::<Vec<_>>();
+            lines.push(format!("Errors   {}.", kinds.join(", ")));
+        }
+        if self.ended.is_some() {
+            lines.push(format!("Sessions updated {}.", self.sessions));
+        }
+        lines
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let duration = (self.ended.unwrap_or_else(Instant::now) - self.started).as_secs_f64();
+        let rows_per_sec = match duration > 0.0 {
+            true => (self.parsed as f64 / duration).round() as u64,
+            false => 0,
+        };
+        json!({
+            "event": if self.ended.is_some() { "summary" } else { "progress" },
+            "parsed": self.parsed,
+            "errors": self.parse_errors,
+            "errors_by_kind": self.parse_error_kinds,
+            "filtered": self.filtered,
+            "unique": self.unique,
+            "bots": self.bots,
+            "inserted": self.insertted,
+            "duplicates": self.duplicates,
+            "insert_errors": self.insert_errors,
+            "sessions": self.sessions,
+            "duration_ms": (duration * 1000.0).round() as u64,
+            "rows_per_sec": rows_per_sec,
+        })
+    }
+}
+
+/// Passes reads through, counting the bytes
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Rows per second over the last `RATE_MS`
+#[derive(Debug)]
+struct Rate {
+    sampled: Instant,
+    rows: usize,
+    per_sec: f64,
+}
+
+impl Rate {
+    fn new() -> Self {
+        Rate {
+            sampled: Instant::now(),
+            rows: 0,
+            per_sec: 0.0,
+        }
+    }
+
+    fn update(&mut self, now: Instant, rows: usize) {
+        let elapsed = now - self.sampled;
+        if elapsed.as_millis() >= RATE_MS {
+            self.per_sec = (rows - self.rows) as f64 / elapsed.as_secs_f64();
+            self.sampled = now;
+            self.rows = rows;
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    format!("{:.1} MiB", mib)
+}
+
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn format_timestamp(timestamp: Option<i64>) -> String {
+    timestamp
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DrawState, ProgressFormat};
+    use loggerson::db::DbError;
+    use loggerson::parser::parse;
+    use loggerson::Msg;
+
+    #[test]
+    fn test_json_summary() {
+        let mut state = DrawState::new(ProgressFormat::Json, true);
+        for msg in [
+            Msg::RowParsed,
+            Msg::RowParsed,
+            Msg::RowUnique,
+            Msg::RowInserted,
+            Msg::DbError(DbError::DuplicateEntry),
+            Msg::SessionsUpdated(1),
+        ] {
+            state.update(msg);
+        }
+        assert_eq!("progress", state.to_json()["event"]);
+
+        state.finish();
+        let summary = state.to_json();
+        assert_eq!("summary", summary["event"]);
+        assert_eq!(2, summary["parsed"]);
+        assert_eq!(1, summary["unique"]);
+        assert_eq!(1, summary["inserted"]);
+        assert_eq!(1, summary["duplicates"]);
+        assert!(summary["duration_ms"].is_u64());
+        assert!(summary["rows_per_sec"].is_u64());
+    }
+
+    #[test]
+    fn test_tty_lines() {
+        let mut state = DrawState::new(ProgressFormat::Tty, true);
+        let mut reader = state.input(&b"0123456789"[..], 20);
+        std::io::Read::read_to_end(&mut reader, &mut Vec::new()).unwrap();
+        state.update(Msg::ChunkQueued {
+            queued: 2,
+            timestamp: Some(1633874136),
+        });
+        let lines = state.to_lines();
+        assert!(lines[0].starts_with("Read     0.0 MiB / 0.0 MiB (50%), ETA "));
+        assert!(lines[3].starts_with("Queue    [##-] 2/3"));
+        assert_eq!("Log time parsed 2021-10-10 13:55:36, inserted -", lines[4]);
+        assert_eq!(5, lines.len());
+
+        for line in [
+            "garbage",
+            "more garbage",
+            "1.2.3 - - [10/Oct/2021:13:55:36 +0000] \"GET / HTTP/1.1\" 200 1 \"-\" \"-\"",
+        ] {
+            state.update(Msg::LogParseError(parse(line, None).unwrap_err()));
+        }
+        assert_eq!("Errors   invalid ip 1, no match 2.", state.to_lines()[5]);
+        assert_eq!(
+            serde_json::json!({"invalid ip": 1, "no match": 2}),
+            state.to_json()["errors_by_kind"]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Lines which failed to parse, written aside with the reason so that they
+//! can be fixed and imported again
+
+use crate::parser::ParseError;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes one tab separated row per rejected line: source, line number,
+/// error kind and the line itself. The line is last, so `cut -f4-` gives back
+/// the original lines.
+pub struct Quarantine<W: Write> {
+    out: W,
+    /// Names of the inputs, by `Location::source`
+    sources: Vec<String>,
+}
+
+impl Quarantine<BufWriter<File>> {
+    /// Truncates the file if it exists
+    pub fn create(
+        path: impl AsRef<Path>,
+        sources: impl IntoIterator<Item = impl Into<String>>,
+    ) -> io::Result<Self> {
+        Ok(Quarantine::new(
+            BufWriter::new(File::create(path)?),
+            sources,
+        ))
+    }
+}
+
+impl<W: Write> Quarantine<W> {
+    pub fn new(out: W, sources: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Quarantine {
+            out,
+            sources: sources.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn write(&mut self, err: &ParseError) -> io::Result<()> {
+        let location = err.location();
+        let line_number = location
+            .line_number
+            .map(|n| n.to_string())
+            .unwrap_or_default();
+        writeln!(
+            self.out,
+            "{}\t{}\t{}\t{}",
+            self.sources
+                .get(location.source)
+                .map(String::as_str)
+                .unwrap_or_default(),
+            line_number,
+            err.kind(),
+            location.line
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quarantine;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_write() {
+        let mut out = Vec::new();
+        let mut quarantine = Quarantine::new(&mut out, ["access_log", "other_log"]);
+        for (source, number, line) in [
+            (0, 1, "garbage"),
+            (
+                0,
+                7,
+                r#"1.2.3 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "-""#,
+            ),
+            (
+                1,
+                9,
+                r#"1.2.3.4 - - [10/Foo/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "-" "-""#,
+            ),
+        ] {
+            let err = parse(line, None)
+                .unwrap_err()
+                .at_line(number)
+                .in_source(source);
+            quarantine.write(&err).unwrap();
+        }
+        assert_eq!(
+            concat!(
+                "access_log\t1\tno match\tgarbage\n",
+                "access_log\t7\tinvalid ip\t1.2.3 - - [10/Oct/2021:13:55:36 +0000] \"GET / HTTP/1.1\" 200 10 \"-\" \"-\"\n",
+                "other_log\t9\tinvalid timestamp\t1.2.3.4 - - [10/Foo/2021:13:55:36 +0000] \"GET / HTTP/1.1\" 200 10 \"-\" \"-\"\n",
+            ),
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Referrer URL parsing and traffic source classification
+
+use crate::db::Result;
+use rusqlite::{params, Connection};
+
+/// Kind of the site the referrer points to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceKind {
+    Search,
+    Social,
+}
+
+impl SourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceKind::Search => "search",
+            SourceKind::Social => "social",
+        }
+    }
+}
+
+/// Hosts of known sources, matching the host and its subdomains. A trailing
+/// `.*` matches any top level domain, e.g. `google.*` matches `google.co.uk`.
+static SOURCES: &[(&str, SourceKind, &str)] = &[
+    ("google.*", SourceKind::Search, "Google"),
+    ("bing.com", SourceKind::Search, "Bing"),
+    ("duckduckgo.com", SourceKind::Search, "DuckDuckGo"),
+    ("search.yahoo.com", SourceKind::Search, "Yahoo"),
+    ("yandex.*", SourceKind::Search, "Yandex"),
+    ("baidu.com", SourceKind::Search, "Baidu"),
+    ("ecosia.org", SourceKind::Search, "Ecosia"),
+    ("startpage.com", SourceKind::Search, "Startpage"),
+    ("qwant.com", SourceKind::Search, "Qwant"),
+    ("search.brave.com", SourceKind::Search, "Brave"),
+    ("kagi.com", SourceKind::Search, "Kagi"),
+    ("facebook.com", SourceKind::Social, "Facebook"),
+    ("instagram.com", SourceKind::Social, "Instagram"),
+    ("twitter.com", SourceKind::Social, "Twitter"),
+    ("t.co", SourceKind::Social, "Twitter"),
+    ("x.com", SourceKind::Social, "Twitter"),
+    ("linkedin.com", SourceKind::Social, "LinkedIn"),
+    ("lnkd.in", SourceKind::Social, "LinkedIn"),
+    ("reddit.com", SourceKind::Social, "Reddit"),
+    ("news.ycombinator.com", SourceKind::Social, "Hacker News"),
+    ("youtube.com", SourceKind::Social, "YouTube"),
+    ("pinterest.*", SourceKind::Social, "Pinterest"),
+    ("tiktok.com", SourceKind::Social, "TikTok"),
+    ("vk.com", SourceKind::Social, "VK"),
+    ("mastodon.social", SourceKind::Social, "Mastodon"),
+];
+
+/// Referrer URL split into parts, parts missing from the URL are `None`
+#[derive(PartialEq, Eq, Debug)]
+pub struct ReferrerParts<'a> {
+    pub scheme: Option<&'a str>,
+    /// Lowercase host without the user info and port
+    pub host: Option<String>,
+    pub path: Option<&'a str>,
+}
+
+pub fn parse_referrer(url: &str) -> ReferrerParts<'_> {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, url),
+    };
+    let path = rest
+        .find(['/', '?', '#'])
+        .map(|i| &rest[i..])
+        .filter(|path| !path.is_empty());
+    let host = referrer_domain(url);
+    ReferrerParts {
+        scheme,
+        host: (!host.is_empty()).then(|| host.to_lowercase()),
+        path,
+    }
+}
+
+/// Host part of the referrer URL, or the whole value if it's not an URL
+pub fn referrer_domain(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let host = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    }
+}
+
+/// Whether the host is `domain` or its subdomain
+fn is_host_of(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.'))
+}
+
+fn matches_source(host: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix(".*") {
+        Some(name) => {
+            let labels: Vec<&str> = host.split('.').collect();
+            labels
+                .iter()
+                .enumerate()
+                .any(|(i, label)| *label == name && is_top_level_domain(&labels[i + 1..]))
+        }
+        None => is_host_of(host, pattern),
+    }
+}
+
+/// Whether the labels look like a top level domain, like `fi` or `co.uk`
+fn is_top_level_domain(labels: &[&str]) -> bool {
+    match labels {
+        [_] => true,
+        [second, _] => ["co", "com", "org", "net", "ac", "or", "ne"].contains(second),
+        _ => false,
+    }
+}
+
+/// Known search engine or social network of the lowercase host
+pub fn classify_host(host: &str) -> Option<(SourceKind, &'static str)> {
+    SOURCES
+        .iter()
+        .find(|(pattern, _, _)| matches_source(host, pattern))
+        .map(|(_, kind, name)| (*kind, *name))
+}
+
+/// Sets `referrer_domains.is_internal` for the domains which are one of the
+/// own hosts or their subdomain, and clears it from the rest
+pub fn mark_internal_domains(con: &Connection, own_hosts: &[String]) -> Result<usize> {
+    let mut stmt = con.prepare_cached(
+        "
+            SELECT id, host FROM referrer_domains
+        ",
+    )?;
+    let domains = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i32, String)>, _>>()?;
+
+    let mut stmt = con.prepare_cached(
+        "
+            UPDATE referrer_domains SET is_internal = ? WHERE id = ?
+        ",
+    )?;
+    let mut internal = 0;
+    for (id, host) in domains {
+        let is_internal = own_hosts
+            .iter()
+            .any(|own| is_host_of(&host, &own.to_lowercase()));
+        stmt.execute(params![is_internal, id])?;
+        internal += is_internal as usize;
+    }
+    Ok(internal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referrer_domain() {
+        assert_eq!("example.com", referrer_domain("https://example.com/foo?x"));
+        assert_eq!(
+            "example.com",
+            referrer_domain("http://user@example.com:8080")
+        );
+        assert_eq!("android-app", referrer_domain("android-app"));
+    }
+
+    #[test]
+    fn test_parse_referrer() {
+        assert_eq!(
+            ReferrerParts {
+                scheme: Some("https"),
+                host: Some("www.example.com".to_owned()),
+                path: Some("/foo?x"),
+            },
+            parse_referrer("https://WWW.Example.com:443/foo?x")
+        );
+        assert_eq!(
+            ReferrerParts {
+                scheme: Some("http"),
+                host: Some("example.com".to_owned()),
+                path: None,
+            },
+            parse_referrer("http://example.com")
+        );
+    }
+
+    #[test]
+    fn test_classify_host() {
+        let name = |host| classify_host(host).map(|(_, name)| name);
+        assert_eq!(Some("Google"), name("www.google.com"));
+        assert_eq!(Some("Google"), name("www.google.co.uk"));
+        assert_eq!(Some("Twitter"), name("t.co"));
+        assert_eq!(Some("Facebook"), name("l.facebook.com"));
+        assert_eq!(None, name("notfacebook.com"));
+        assert_eq!(None, name("google.example.com"));
+        assert_eq!(
+            Some(SourceKind::Search),
+            classify_host("duckduckgo.com").map(|(kind, _)| kind)
+        );
+    }
+}
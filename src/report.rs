@@ -0,0 +1,799 @@
+use crate::db::Result;
+use crate::filter::parse_time;
+use crate::sketches::{register_hll_count, users_between, DAY};
+use chrono::{DateTime, FixedOffset};
+use chrono_tz::Tz;
+use clap::{Args, ValueEnum};
+use itertools::Itertools;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::io::{self, Write};
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportKind {
+    /// Most requested URLs. From the daily rollups the users are estimated
+    /// from the sketches, in an `approx_users` column.
+    TopUrls,
+    /// Most common referrer domains
+    TopReferrers,
+    /// Entries by traffic source: direct, internal, search, social or other
+    TrafficSources,
+    /// Entries per status code
+    Status,
+    /// Unique users per day, week or month
+    UniqueUsers,
+    /// Entries and unique users per hour of the day
+    Hours,
+    /// Users by the time between their first and last entry
+    UserLifetime,
+    /// Approximate unique users of the whole range from the daily sketches,
+    /// works after the user hashes are pruned. Bots are always included
+    ApproxUsers,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+/// Time zone of the periods and hours
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReportTz {
+    Utc,
+    /// Offset logged with each entry
+    Logged,
+    /// IANA zone from the embedded tz database
+    Zone(Tz),
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    /// Aligned table
+    Table,
+    Csv,
+    /// Array of objects
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct ReportArgs {
+    #[arg(value_enum)]
+    pub kind: ReportKind,
+
+    /// SQLite database file
+    #[arg(long, default_value = ".cache.db")]
+    pub db: String,
+
+    /// Start of the time range, inclusive. RFC 3339 time or `YYYY-MM-DD`
+    #[arg(long, value_parser = parse_time_arg)]
+    pub from: Option<i64>,
+
+    /// End of the time range, exclusive. RFC 3339 time or `YYYY-MM-DD`
+    #[arg(long, value_parser = parse_time_arg)]
+    pub to: Option<i64>,
+
+    /// Maximum number of rows
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+
+    /// Period of the unique users report
+    #[arg(long, value_enum, default_value_t = Period::Day)]
+    pub period: Period,
+
+    /// Time zone of the periods and hours: `utc`, `logged` for the offset in
+    /// each log line, or an IANA zone such as `Europe/Helsinki`
+    #[arg(long, value_parser = parse_tz_arg, default_value = "utc")]
+    pub tz: ReportTz,
+
+    /// Leave out users marked as bots
+    #[arg(long)]
+    pub exclude_bots: bool,
+
+    /// Leave out referrers from the own hosts given to the import
+    #[arg(long)]
+    pub exclude_internal: bool,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+pub fn parse_time_arg(text: &str) -> Result<i64, String> {
+    parse_time(text).ok_or_else(|| format!("invalid time '{}'", text))
+}
+
+pub fn parse_tz_arg(text: &str) -> Result<ReportTz, String> {
+    match text {
+        "utc" | "UTC" => Ok(ReportTz::Utc),
+        "logged" => Ok(ReportTz::Logged),
+        _ => text
+            .parse()
+            .map(ReportTz::Zone)
+            .map_err(|_| format!("unknown time zone '{}'", text)),
+    }
+}
+
+/// Registers `local_strftime(format, timestamp, utc_offset)` which formats
+/// the time in the zone of the report, `utc_offset` is the logged one
+fn register_local_strftime(con: &Connection, tz: ReportTz) -> Result<()> {
+    con.create_scalar_function(
+        "local_strftime",
+        3,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| {
+            let format = ctx.get::<String>(0)?;
+            let utc = DateTime::from_timestamp(ctx.get(1)?, 0).ok_or_else(|| {
+                rusqlite::Error::UserFunctionError("timestamp out of range".into())
+            })?;
+            let local = match tz {
+                ReportTz::Utc => utc.format(&format),
+                ReportTz::Logged => match FixedOffset::east_opt(ctx.get(2)?) {
+                    Some(offset) => utc.with_timezone(&offset).format(&format),
+                    None => utc.format(&format),
+                },
+                ReportTz::Zone(zone) => utc.with_timezone(&zone).format(&format),
+            };
+            Ok(local.to_string())
+        },
+    )?;
+    Ok(())
+}
+
+/// Report output, values are kept as SQLite values
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    fn query(
+        con: &Connection,
+        columns: Vec<&'static str>,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Self> {
+        let mut stmt = con.prepare(sql)?;
+        let rows = stmt
+            .query_map(params, |row| {
+                (0..columns.len()).map(|i| row.get::<_, Value>(i)).collect()
+            })?
+            .collect::<Result<Vec<Vec<Value>>, _>>()?;
+        Ok(Table { columns, rows })
+    }
+
+    pub fn write(&self, format: OutputFormat, out: &mut impl Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Table => self.write_table(out),
+            OutputFormat::Csv => self.write_csv(out),
+            OutputFormat::Json => self.write_json(out),
+        }
+    }
+
+    fn write_table(&self, out: &mut impl Write) -> io::Result<()> {
+        let header = self.columns.iter().map(|c| c.to_string()).collect_vec();
+        let cells = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(to_text).collect_vec())
+            .collect_vec();
+        let widths = (0..self.columns.len())
+            .map(|i| {
+                cells
+                    .iter()
+                    .chain([&header])
+                    .map(|row| row[i].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect_vec();
+
+        // Numbers are aligned right
+        let numeric = (0..self.columns.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .all(|row| matches!(row[i], Value::Integer(_) | Value::Real(_) | Value::Null))
+            })
+            .collect_vec();
+
+        let separator = widths.iter().map(|w| "-".repeat(*w)).collect_vec();
+        write_aligned(out, &header, &widths, &numeric)?;
+        write_aligned(out, &separator, &widths, &numeric)?;
+        for row in &cells {
+            write_aligned(out, row, &widths, &numeric)?;
+        }
+        Ok(())
+    }
+
+    fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{}",
+            self.columns.iter().map(|c| csv_escape(c)).join(",")
+        )?;
+        for row in &self.rows {
+            writeln!(
+                out,
+                "{}",
+                row.iter().map(|v| csv_escape(&to_text(v))).join(",")
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        let objects = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| (column.to_string(), to_json(value)))
+                    .collect::<serde_json::Map<_, _>>()
+            })
+            .collect_vec();
+        serde_json::to_writer_pretty(&mut *out, &objects)?;
+        writeln!(out)
+    }
+}
+
+fn write_aligned(
+    out: &mut impl Write,
+    cells: &[String],
+    widths: &[usize],
+    numeric: &[bool],
+) -> io::Result<()> {
+    let line = cells
+        .iter()
+        .zip(widths)
+        .zip(numeric)
+        .map(|((cell, width), numeric)| {
+            if *numeric {
+                format!("{:>width$}", cell, width = width)
+            } else {
+                format!("{:<width$}", cell, width = width)
+            }
+        })
+        .join("  ");
+    writeln!(out, "{}", line.trim_end())
+}
+
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => "".to_owned(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => format!("{:.2}", f),
+        Value::Text(s) => s.clone(),
+        Value::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null | Value::Blob(_) => serde_json::Value::Null,
+        Value::Integer(i) => (*i).into(),
+        Value::Real(f) => (*f).into(),
+        Value::Text(s) => s.clone().into(),
+    }
+}
+
+pub fn csv_escape(value: &str) -> String {
+    if value.contains(['"', ',', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Whether the time is at the start of a UTC day, which the daily rollups
+/// are bucketed by. No limit is aligned.
+fn day_aligned(time: Option<i64>) -> bool {
+    time.is_none_or(|time| time.rem_euclid(DAY) == 0)
+}
+
+pub fn run(con: &Connection, args: &ReportArgs) -> Result<Table> {
+    register_local_strftime(con, args.tz)?;
+    register_hll_count(con)?;
+    let from = args.from.unwrap_or(i64::MIN);
+    let to = args.to.unwrap_or(i64::MAX);
+    let limit = args.limit as i64;
+
+    // Users marked as bots are left out with a subquery, if wanted
+    let bots = if args.exclude_bots {
+        "AND e.user_id IN (SELECT id FROM users WHERE is_bot = 0)"
+    } else {
+        ""
+    };
+
+    let internal = if args.exclude_internal {
+        "AND IFNULL(d.is_internal, 0) = 0"
+    } else {
+        ""
+    };
+
+    // The daily rollups count hits and users without reading the entries,
+    // they keep no user so bots can't be left out
+    let rollups = !args.exclude_bots && day_aligned(args.from) && day_aligned(args.to);
+
+    match args.kind {
+        // Users of the url are estimated by merging its daily sketches
+        ReportKind::TopUrls if rollups => Table::query(
+            con,
+            vec!["url", "hits", "approx_users"],
+            "
+            WITH top AS (
+                SELECT r.url, SUM(o.hits) as hits
+                FROM rollup_requests o, requests r
+                WHERE o.request_id = r.id AND o.period = 'day'
+                    AND o.bucket >= ?1 AND o.bucket < ?2
+                GROUP BY r.url
+                ORDER BY hits DESC, r.url
+                LIMIT ?3
+            )
+            SELECT t.url, t.hits, (
+                SELECT hll_count(s.sketch)
+                FROM sketches_requests s, requests r
+                WHERE s.request_id = r.id AND r.url = t.url
+                    AND s.day >= ?1 AND s.day < ?2
+            ) as users
+            FROM top t
+            ORDER BY t.hits DESC, t.url
+            ",
+            params![from, to, limit],
+        ),
+        ReportKind::TopUrls => Table::query(
+            con,
+            vec!["url", "hits", "users"],
+            &format!(
+                "
+                SELECT r.url, SUM(e.hit_count) as hits, COUNT(DISTINCT e.user_id) as users
+                FROM entrys e, requests r
+                WHERE e.request_id = r.id AND e.timestamp >= ? AND e.timestamp < ? {}
+                GROUP BY r.url
+                ORDER BY hits DESC, r.url
+                LIMIT ?
+                ",
+                bots
+            ),
+            params![from, to, limit],
+        ),
+        ReportKind::TopReferrers if rollups => Table::query(
+            con,
+            vec!["domain", "hits"],
+            &format!(
+                "
+                SELECT d.host, SUM(o.hits) as hits
+                FROM rollup_referrers o, referrers rr, referrer_domains d
+                WHERE o.referrer_id = rr.id AND rr.domain_id = d.id AND o.period = 'day'
+                    AND o.bucket >= ? AND o.bucket < ? {}
+                GROUP BY d.host
+                ORDER BY hits DESC, d.host
+                LIMIT ?
+                ",
+                internal
+            ),
+            params![from, to, limit],
+        ),
+        ReportKind::TopReferrers => Table::query(
+            con,
+            vec!["domain", "hits"],
+            &format!(
+                "
+                SELECT d.host, SUM(e.hit_count) as hits
+                FROM entrys e, referrers rr, referrer_domains d
+                WHERE e.referrer_id = rr.id AND rr.domain_id = d.id
+                    AND e.timestamp >= ? AND e.timestamp < ? {} {}
+                GROUP BY d.host
+                ORDER BY hits DESC, d.host
+                LIMIT ?
+                ",
+                bots, internal
+            ),
+            params![from, to, limit],
+        ),
+        ReportKind::TrafficSources => Table::query(
+            con,
+            vec!["source", "name", "hits", "users"],
+            &format!(
+                "
+                SELECT
+                    CASE
+                        WHEN e.referrer_id IS NULL THEN 'direct'
+                        WHEN d.is_internal THEN 'internal'
+                        ELSE IFNULL(d.source_kind, 'other')
+                    END as kind,
+                    d.source_name as name,
+                    SUM(e.hit_count) as hits, COUNT(DISTINCT e.user_id) as users
+                FROM entrys e
+                LEFT JOIN referrers rr ON e.referrer_id = rr.id
+                LEFT JOIN referrer_domains d ON rr.domain_id = d.id
+                WHERE e.timestamp >= ? AND e.timestamp < ? {} {}
+                GROUP BY kind, name
+                ORDER BY hits DESC, kind, name
+                LIMIT ?
+                ",
+                bots, internal
+            ),
+            params![from, to, limit],
+        ),
+        ReportKind::Status if rollups => Table::query(
+            con,
+            vec!["status_code", "hits", "share"],
+            "
+            SELECT o.status_code, SUM(o.hits) as hits,
+                100.0 * SUM(o.hits) / SUM(SUM(o.hits)) OVER () as share
+            FROM rollup_statuses o
+            WHERE o.period = 'day' AND o.bucket >= ? AND o.bucket < ?
+            GROUP BY o.status_code
+            ORDER BY hits DESC, o.status_code
+            LIMIT ?
+            ",
+            params![from, to, limit],
+        ),
+        ReportKind::Status => Table::query(
+            con,
+            vec!["status_code", "hits", "share"],
+            &format!(
+                "
+                SELECT r.status_code, SUM(e.hit_count) as hits,
+                    100.0 * SUM(e.hit_count) / SUM(SUM(e.hit_count)) OVER () as share
+                FROM entrys e, requests r
+                WHERE e.request_id = r.id AND e.timestamp >= ? AND e.timestamp < ? {}
+                GROUP BY r.status_code
+                ORDER BY hits DESC, r.status_code
+                LIMIT ?
+                ",
+                bots
+            ),
+            params![from, to, limit],
+        ),
+        // Each user has one browser family, so the users of the families add
+        // up to the users of the day
+        ReportKind::UniqueUsers
+            if rollups && args.period == Period::Day && args.tz == ReportTz::Utc =>
+        {
+            Table::query(
+                con,
+                vec!["period", "users", "hits"],
+                "
+                SELECT strftime('%Y-%m-%d', o.bucket, 'unixepoch') as day,
+                    SUM(o.users) as users, SUM(o.hits) as hits
+                FROM rollup_browsers o
+                WHERE o.period = 'day' AND o.bucket >= ? AND o.bucket < ?
+                GROUP BY day
+                ORDER BY day DESC
+                LIMIT ?
+                ",
+                params![from, to, limit],
+            )
+        }
+        ReportKind::UniqueUsers => {
+            let period_format = match args.period {
+                Period::Day => "%Y-%m-%d",
+                Period::Week => "%Y-W%W",
+                Period::Month => "%Y-%m",
+            };
+            Table::query(
+                con,
+                vec!["period", "users", "hits"],
+                &format!(
+                    "
+                    SELECT local_strftime(?, e.timestamp, e.utc_offset) as period,
+                        COUNT(DISTINCT e.user_id) as users, SUM(e.hit_count) as hits
+                    FROM entrys e
+                    WHERE e.timestamp >= ? AND e.timestamp < ? {}
+                    GROUP BY period
+                    ORDER BY period DESC
+                    LIMIT ?
+                    ",
+                    bots
+                ),
+                params![period_format, from, to, limit],
+            )
+        }
+        // All hours, without the limit
+        ReportKind::Hours => Table::query(
+            con,
+            vec!["hour", "hits", "users"],
+            &format!(
+                "
+                SELECT CAST(local_strftime('%H', e.timestamp, e.utc_offset) AS INTEGER) as hour,
+                    SUM(e.hit_count) as hits, COUNT(DISTINCT e.user_id) as users
+                FROM entrys e
+                WHERE e.timestamp >= ? AND e.timestamp < ? {}
+                GROUP BY hour
+                ORDER BY hour
+                ",
+                bots
+            ),
+            params![from, to],
+        ),
+        ReportKind::UserLifetime => Table::query(
+            con,
+            vec!["duration_days", "hits", "useragent"],
+            &format!(
+                "
+                SELECT (MAX(e.timestamp) - MIN(e.timestamp)) / (3600 * 24) as duration_days,
+                    SUM(e.hit_count) as hits, ua.value
+                FROM entrys e, users u
+                LEFT JOIN useragents ua ON u.useragent_id = ua.id
+                WHERE e.user_id = u.id AND e.timestamp >= ? AND e.timestamp < ? {}
+                GROUP BY u.id
+                ORDER BY duration_days DESC, hits DESC
+                LIMIT ?
+                ",
+                bots
+            ),
+            params![from, to, limit],
+        ),
+        ReportKind::ApproxUsers => {
+            let (hll, days) = users_between(con, from, to)?;
+            Ok(Table {
+                columns: vec!["days", "users"],
+                rows: vec![vec![
+                    Value::Integer(days as i64),
+                    Value::Integer(hll.count() as i64),
+                ]],
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tz_arg, run, OutputFormat, Period, ReportArgs, ReportKind, ReportTz, Table};
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::importer::Dedup;
+    use crate::models::*;
+    use crate::referrers::mark_internal_domains;
+    use rusqlite::types::Value;
+
+    fn entry(timestamp: i64, hash: i64, url: &str, referrer: Option<&str>) -> LogEntry<'static> {
+        let mut entry = LogEntry::test(timestamp, hash, url);
+        if url == "/missing" {
+            entry.request.status_code = 404;
+        }
+        entry.referrer = referrer.map(|url| Referrer {
+            url: url.to_owned().into(),
+        });
+        entry
+    }
+
+    fn args(kind: ReportKind) -> ReportArgs {
+        ReportArgs {
+            kind,
+            db: "".to_owned(),
+            from: None,
+            to: None,
+            limit: 20,
+            period: Period::Day,
+            tz: ReportTz::Utc,
+            exclude_bots: false,
+            exclude_internal: false,
+            format: OutputFormat::Table,
+        }
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_owned())
+    }
+
+    #[test]
+    fn test_reports() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let entries = vec![
+            entry(0, 1, "/", Some("https://www.google.com/search?q=x")),
+            entry(10, 1, "/about", Some("https://example.com/")),
+            entry(20, 2, "/", Some("https://www.google.com/")),
+            entry(90000, 2, "/missing", None),
+        ];
+        batch_insert(&sender, &con, &entries, &mut caches, Dedup::Strict).unwrap();
+
+        let table = run(&con, &args(ReportKind::TopUrls)).unwrap();
+        assert_eq!(
+            vec![
+                vec![text("/"), Value::Integer(2), Value::Integer(2)],
+                vec![text("/about"), Value::Integer(1), Value::Integer(1)],
+                vec![text("/missing"), Value::Integer(1), Value::Integer(1)],
+            ],
+            table.rows
+        );
+
+        let table = run(&con, &args(ReportKind::TopReferrers)).unwrap();
+        assert_eq!(
+            vec![
+                vec![text("www.google.com"), Value::Integer(2)],
+                vec![text("example.com"), Value::Integer(1)],
+            ],
+            table.rows
+        );
+
+        mark_internal_domains(&con, &["Example.com".to_owned()]).unwrap();
+        let table = run(&con, &args(ReportKind::TrafficSources)).unwrap();
+        assert_eq!(
+            vec![
+                vec![
+                    text("search"),
+                    text("Google"),
+                    Value::Integer(2),
+                    Value::Integer(2)
+                ],
+                vec![
+                    text("direct"),
+                    Value::Null,
+                    Value::Integer(1),
+                    Value::Integer(1)
+                ],
+                vec![
+                    text("internal"),
+                    Value::Null,
+                    Value::Integer(1),
+                    Value::Integer(1)
+                ],
+            ],
+            table.rows
+        );
+        let mut top_referrers = args(ReportKind::TopReferrers);
+        top_referrers.exclude_internal = true;
+        let table = run(&con, &top_referrers).unwrap();
+        assert_eq!(
+            vec![vec![text("www.google.com"), Value::Integer(2)]],
+            table.rows
+        );
+
+        let table = run(&con, &args(ReportKind::UniqueUsers)).unwrap();
+        assert_eq!(
+            vec![
+                vec![text("1970-01-02"), Value::Integer(1), Value::Integer(1)],
+                vec![text("1970-01-01"), Value::Integer(2), Value::Integer(3)],
+            ],
+            table.rows
+        );
+
+        let mut unique_users = args(ReportKind::UniqueUsers);
+        unique_users.tz = parse_tz_arg("America/Los_Angeles").unwrap();
+        let table = run(&con, &unique_users).unwrap();
+        assert_eq!(
+            vec![
+                vec![text("1970-01-01"), Value::Integer(1), Value::Integer(1)],
+                vec![text("1969-12-31"), Value::Integer(2), Value::Integer(3)],
+            ],
+            table.rows
+        );
+
+        let table = run(&con, &args(ReportKind::Hours)).unwrap();
+        assert_eq!(
+            vec![
+                vec![Value::Integer(0), Value::Integer(3), Value::Integer(2)],
+                vec![Value::Integer(1), Value::Integer(1), Value::Integer(1)],
+            ],
+            table.rows
+        );
+        // +05:30, the half hour moves the later entry to the next hour
+        let mut hours = args(ReportKind::Hours);
+        hours.tz = parse_tz_arg("Asia/Kolkata").unwrap();
+        let table = run(&con, &hours).unwrap();
+        assert_eq!(
+            vec![
+                vec![Value::Integer(5), Value::Integer(3), Value::Integer(2)],
+                vec![Value::Integer(6), Value::Integer(1), Value::Integer(1)],
+            ],
+            table.rows
+        );
+        assert!(parse_tz_arg("Mars/Olympus").is_err());
+
+        let table = run(&con, &args(ReportKind::ApproxUsers)).unwrap();
+        assert_eq!(vec![vec![Value::Integer(2), Value::Integer(2)]], table.rows);
+
+        let mut status = args(ReportKind::Status);
+        status.to = Some(100);
+        let table = run(&con, &status).unwrap();
+        assert_eq!(
+            vec![vec![
+                Value::Integer(200),
+                Value::Integer(3),
+                Value::Real(100.0)
+            ]],
+            table.rows
+        );
+
+        let mut lifetime = args(ReportKind::UserLifetime);
+        lifetime.limit = 1;
+        let table = run(&con, &lifetime).unwrap();
+        assert_eq!(
+            vec![vec![Value::Integer(1), Value::Integer(2), Value::Null]],
+            table.rows
+        );
+    }
+
+    #[test]
+    fn test_reports_from_rollups() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let entries = vec![
+            entry(0, 1, "/", Some("https://www.google.com/search?q=x")),
+            entry(10, 1, "/about", Some("https://example.com/")),
+            entry(20, 2, "/", Some("https://www.google.com/")),
+            entry(30, 1, "/", None),
+            entry(90000, 2, "/missing", None),
+            entry(90010, 3, "/", None),
+        ];
+        batch_insert(&sender, &con, &entries, &mut caches, Dedup::Strict).unwrap();
+
+        let mut second_day = args(ReportKind::TopUrls);
+        second_day.from = Some(86400);
+        let reports = [
+            args(ReportKind::TopUrls),
+            second_day,
+            args(ReportKind::TopReferrers),
+            args(ReportKind::Status),
+            args(ReportKind::UniqueUsers),
+        ];
+        let mut unaligned = args(ReportKind::Status);
+        unaligned.to = Some(100);
+        let tables = reports
+            .iter()
+            .map(|args| run(&con, args).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                vec![text("/"), Value::Integer(4), Value::Integer(3)],
+                vec![text("/about"), Value::Integer(1), Value::Integer(1)],
+                vec![text("/missing"), Value::Integer(1), Value::Integer(1)],
+            ],
+            tables[0].rows
+        );
+        assert_eq!(vec!["url", "hits", "approx_users"], tables[0].columns);
+        assert_eq!(
+            vec![
+                vec![text("1970-01-02"), Value::Integer(2), Value::Integer(2)],
+                vec![text("1970-01-01"), Value::Integer(2), Value::Integer(4)],
+            ],
+            tables[4].rows
+        );
+
+        // Ranges the rollups can't answer still read the entries
+        assert_eq!(1, run(&con, &unaligned).unwrap().rows.len());
+        con.execute("DELETE FROM entrys", []).unwrap();
+        for (args, table) in reports.iter().zip(tables) {
+            assert_eq!(table, run(&con, args).unwrap());
+        }
+        assert!(run(&con, &unaligned).unwrap().rows.is_empty());
+    }
+
+    #[test]
+    fn test_write_formats() {
+        let table = Table {
+            columns: vec!["url", "hits"],
+            rows: vec![
+                vec![text("/"), Value::Integer(120)],
+                vec![text("/a,\"b\""), Value::Integer(3)],
+            ],
+        };
+        let write = |format| {
+            let mut out = Vec::new();
+            table.write(format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            "url     hits\n------  ----\n/        120\n/a,\"b\"     3\n",
+            write(OutputFormat::Table)
+        );
+        assert_eq!(
+            "url,hits\n/,120\n\"/a,\"\"b\"\"\",3\n",
+            write(OutputFormat::Csv)
+        );
+        let json: serde_json::Value = serde_json::from_str(&write(OutputFormat::Json)).unwrap();
+        assert_eq!(
+            serde_json::json!([{"url": "/", "hits": 120}, {"url": "/a,\"b\"", "hits": 3}]),
+            json
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Hourly and daily rollups of hits and unique users
+//!
+//! Rollups are updated in the same transaction as the entries are inserted,
+//! from the entries with id greater than the last id before the batch. Users
+//! are counted as unique if they have no older entry in the same bucket with
+//! the same key, so the raw entries of the buckets still being imported must
+//! not be expired.
+
+use crate::db::Result;
+use rusqlite::{params, Connection};
+
+static PERIODS: &[(&str, i64)] = &[("hour", 3600), ("day", 86400)];
+
+struct Dimension {
+    table: &'static str,
+    key: &'static str,
+    key_expr: &'static str,
+    from: &'static str,
+    /// Condition for an older entry `o` to have the same key as entry `e`
+    same_key: &'static str,
+}
+
+static DIMENSIONS: &[Dimension] = &[
+    Dimension {
+        table: "rollup_requests",
+        key: "request_id",
+        key_expr: "e.request_id",
+        from: "entrys e",
+        same_key: "o.request_id = e.request_id",
+    },
+    Dimension {
+        table: "rollup_referrers",
+        key: "referrer_id",
+        key_expr: "e.referrer_id",
+        from: "entrys e",
+        same_key: "o.referrer_id = e.referrer_id",
+    },
+    Dimension {
+        table: "rollup_statuses",
+        key: "status_code",
+        key_expr: "r.status_code",
+        from: "entrys e JOIN requests r ON e.request_id = r.id",
+        same_key: "o.request_id IN (SELECT id FROM requests WHERE status_code = r.status_code)",
+    },
+    Dimension {
+        table: "rollup_browsers",
+        key: "browser_family",
+        key_expr: "IFNULL(d.browser_family, 'Other')",
+        from: "entrys e JOIN users u ON e.user_id = u.id
+            LEFT JOIN useragent_details d ON u.useragent_id = d.useragent_id",
+        // Useragent is part of the user, so any older entry has the same key
+        same_key: "1",
+    },
+];
+
+/// Id of the last entry, rollups are updated for entries after it
+pub fn last_entry_id(con: &Connection) -> Result<i64> {
+    Ok(
+        con.query_row("SELECT IFNULL(MAX(id), 0) FROM entrys", [], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+/// Adds the entries inserted after `after_id` to the rollups
+pub fn update_rollups(con: &Connection, after_id: i64) -> Result<()> {
+    for dimension in DIMENSIONS {
+        let sql = format!(
+            "
+            INSERT INTO {table}(period, bucket, {key}, hits, users)
+            SELECT ?1, e.timestamp / ?2 * ?2 as b, {key_expr}, SUM(e.hit_count),
+                COUNT(DISTINCT CASE WHEN NOT EXISTS (
+                    SELECT 1 FROM entrys o
+                    WHERE o.user_id = e.user_id
+                        AND o.timestamp >= e.timestamp / ?2 * ?2
+                        AND o.timestamp < e.timestamp / ?2 * ?2 + ?2
+                        AND o.id <= ?3
+                        AND {same_key}
+                ) THEN e.user_id END)
+            FROM {from}
+            WHERE e.id > ?3 AND {key_expr} IS NOT NULL
+            GROUP BY b, {key_expr}
+            ON CONFLICT (period, bucket, {key}) DO UPDATE SET
+                hits = hits + excluded.hits,
+                users = users + excluded.users
+            ",
+            table = dimension.table,
+            key = dimension.key,
+            key_expr = dimension.key_expr,
+            from = dimension.from,
+            same_key = dimension.same_key,
+        );
+        let mut stmt = con.prepare_cached(&sql)?;
+        for (period, seconds) in PERIODS {
+            stmt.execute(params![period, seconds, after_id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds hits of repeats counted to an entry which is already in the rollups,
+/// with `Dedup::Count`
+pub fn add_repeat_hits(con: &Connection, entry_id: i64, hits: i32) -> Result<()> {
+    for dimension in DIMENSIONS {
+        let sql = format!(
+            "
+            UPDATE {table} SET hits = hits + ?1
+            WHERE (period, bucket, {key}) IN (
+                SELECT ?2, e.timestamp / ?3 * ?3, {key_expr}
+                FROM {from}
+                WHERE e.id = ?4
+            )
+            ",
+            table = dimension.table,
+            key = dimension.key,
+            key_expr = dimension.key_expr,
+            from = dimension.from,
+        );
+        let mut stmt = con.prepare_cached(&sql)?;
+        for (period, seconds) in PERIODS {
+            stmt.execute(params![hits, period, seconds, entry_id])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::importer::Dedup;
+    use crate::models::*;
+    use itertools::Itertools;
+
+    fn entry(timestamp: i64, hash: i64, url: &str) -> LogEntry<'static> {
+        let mut entry = LogEntry::test(timestamp, hash, url);
+        entry.user.useragent = Some(Useragent {
+            value: "Mozilla/5.0 (X11; Linux x86_64; rv:95.0) Gecko/20100101 Firefox/95.0".into(),
+        });
+        entry
+    }
+
+    #[test]
+    fn test_update_rollups() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+
+        let first = vec![entry(0, 1, "/"), entry(10, 1, "/"), entry(20, 2, "/about")];
+        batch_insert(&sender, &con, &first, &mut caches, Dedup::Strict).unwrap();
+
+        // User 1 is already counted for the first hour, user 3 is new
+        let second = vec![entry(30, 1, "/"), entry(40, 3, "/"), entry(4000, 1, "/")];
+        batch_insert(&sender, &con, &second, &mut caches, Dedup::Strict).unwrap();
+
+        let rollup = |sql: &str| {
+            let mut stmt = con.prepare(sql).unwrap();
+            stmt.query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .flatten()
+            .collect_vec()
+        };
+        let requests: Vec<(i64, String, i64, i64)> = rollup(
+            "
+            SELECT o.bucket, r.url, o.hits, o.users
+            FROM rollup_requests o, requests r
+            WHERE o.request_id = r.id AND o.period = 'hour'
+            ORDER BY o.bucket, r.url
+            ",
+        );
+        assert_eq!(
+            vec![
+                (0, "/".to_owned(), 4, 2),
+                (0, "/about".to_owned(), 1, 1),
+                (3600, "/".to_owned(), 1, 1),
+            ],
+            requests
+        );
+
+        let browsers: Vec<(i64, String, i64, i64)> = rollup(
+            "
+            SELECT bucket, period, hits, users
+            FROM rollup_browsers
+            WHERE browser_family = 'Firefox'
+            ORDER BY period, bucket
+            ",
+        );
+        assert_eq!(
+            vec![
+                (0, "day".to_owned(), 6, 3),
+                (0, "hour".to_owned(), 5, 3),
+                (3600, "hour".to_owned(), 1, 1),
+            ],
+            browsers
+        );
+    }
+
+    #[test]
+    fn test_add_repeat_hits() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+
+        let mut repeated = entry(0, 1, "/");
+        repeated.hits = 2;
+        batch_insert(
+            &sender,
+            &con,
+            &[repeated.clone()],
+            &mut caches,
+            Dedup::Count,
+        )
+        .unwrap();
+        batch_insert(&sender, &con, &[repeated], &mut caches, Dedup::Count).unwrap();
+
+        let (hit_count, hits): (i64, i64) = con
+            .query_row(
+                "
+                SELECT e.hit_count, o.hits
+                FROM entrys e, rollup_requests o
+                WHERE o.request_id = e.request_id AND o.period = 'day'
+                ",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((4, 4), (hit_count, hits));
+    }
+}
@@ -12,16 +12,46 @@
 CREATE TABLE IF NOT EXISTS entrys (
   id              INTEGER PRIMARY KEY AUTOINCREMENT,
   timestamp       BIGINT          NOT NULL,
+  -- microseconds past the timestamp, 0 unless imported with sub-second precision
+  micros          INTEGER         NOT NULL DEFAULT 0,
+  -- offset of the logged time from UTC in seconds
+  utc_offset      INTEGER         NOT NULL DEFAULT 0,
+  -- line number in the input with `--dedup line`, 0 otherwise
+  line_number     BIGINT          NOT NULL DEFAULT 0,
+  -- index of the input among several merged ones with `--dedup line`
+  source          INTEGER         NOT NULL DEFAULT 0,
+  -- identical entries counted to this one with `--dedup count`
+  hit_count       INTEGER         NOT NULL DEFAULT 1,
   request_id      INTEGER         NOT NULL,
   user_id         INTEGER         NOT NULL,
   -- referrer is intentionally nullable
   referrer_id     INTEGER,        
+  -- session is assigned after the import, NULL until then
+  session_id      INTEGER,
   FOREIGN KEY (request_id) REFERENCES requests(id),
   FOREIGN KEY (user_id) REFERENCES users(id),
   FOREIGN KEY (referrer_id) REFERENCES referrers(id),
-  UNIQUE (timestamp, request_id, user_id)
+  FOREIGN KEY (session_id) REFERENCES sessions(id),
+  UNIQUE (timestamp, micros, source, line_number, request_id, user_id)
 );
-CREATE INDEX IF NOT EXISTS entrys_cols ON entrys(timestamp, request_id, user_id);
+CREATE INDEX IF NOT EXISTS entrys_cols ON entrys(timestamp, micros, source, line_number, request_id, user_id);
+CREATE INDEX IF NOT EXISTS entrys_user ON entrys(user_id, timestamp);
+CREATE INDEX IF NOT EXISTS entrys_unsessioned ON entrys(user_id) WHERE session_id IS NULL;
+
+-- run of entries of one user, with gaps less than the session gap
+CREATE TABLE IF NOT EXISTS sessions (
+  id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id             INTEGER   NOT NULL,
+  start_timestamp     BIGINT    NOT NULL,
+  end_timestamp       BIGINT    NOT NULL,
+  entry_count         INTEGER   NOT NULL,
+  landing_request_id  INTEGER   NOT NULL,
+  exit_request_id     INTEGER   NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id),
+  FOREIGN KEY (landing_request_id) REFERENCES requests(id),
+  FOREIGN KEY (exit_request_id) REFERENCES requests(id)
+);
+CREATE INDEX IF NOT EXISTS sessions_user ON sessions(user_id, start_timestamp);
 
 CREATE TABLE IF NOT EXISTS requests (
   id              INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -40,9 +70,16 @@ CREATE TABLE IF NOT EXISTS users (
 
   -- useragent_id intentionally allows NULL, so you can forget the useragent
   useragent_id    INTEGER,
-  FOREIGN KEY(useragent_id) REFERENCES useragents(id)
+
+  -- country is looked up from the IP before it's hashed, NULL if unknown
+  country_id      INTEGER,
+
+  -- set when the import detects the user as a bot or crawler
+  is_bot          BOOLEAN   NOT NULL DEFAULT 0,
+  FOREIGN KEY(useragent_id) REFERENCES useragents(id),
+  FOREIGN KEY(country_id) REFERENCES countries(id)
 );
-CREATE INDEX IF NOT EXISTS users_cols ON users(hash, useragent_id);
+CREATE INDEX IF NOT EXISTS users_cols ON users(hash, useragent_id, country_id);
 
 CREATE TABLE IF NOT EXISTS useragents (
   id              INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -50,14 +87,110 @@ CREATE TABLE IF NOT EXISTS useragents (
 );
 CREATE INDEX IF NOT EXISTS useragents_value ON useragents(value);
 
+-- classified once per useragent with the bundled uap-core regexes
+CREATE TABLE IF NOT EXISTS useragent_details (
+  useragent_id    INTEGER PRIMARY KEY,
+  browser_family  TEXT      NOT NULL,
+  browser_major   TEXT,
+  os_family       TEXT      NOT NULL,
+  device_type     TEXT      NOT NULL,
+  is_bot          BOOLEAN   NOT NULL,
+  FOREIGN KEY (useragent_id) REFERENCES useragents(id)
+);
+
 CREATE TABLE IF NOT EXISTS referrers (
   id              INTEGER PRIMARY KEY AUTOINCREMENT,
-  url             TEXT     NOT NULL UNIQUE
+  url             TEXT     NOT NULL UNIQUE,
+
+  -- parts of the url, NULL if missing from it
+  scheme          TEXT,
+  domain_id       INTEGER,
+  path            TEXT,
+  FOREIGN KEY (domain_id) REFERENCES referrer_domains(id)
 );
 CREATE INDEX IF NOT EXISTS referrer_url ON referrers(url);
+CREATE INDEX IF NOT EXISTS referrer_domain ON referrers(domain_id);
+
+CREATE TABLE IF NOT EXISTS referrer_domains (
+  id              INTEGER PRIMARY KEY AUTOINCREMENT,
+  -- lowercase host without port
+  host            TEXT      NOT NULL UNIQUE,
+
+  -- 'search' or 'social' for known sites, NULL otherwise
+  source_kind     TEXT,
+  source_name     TEXT,
+
+  -- set when the host is one of the own hosts given to the import
+  is_internal     BOOLEAN   NOT NULL DEFAULT 0
+);
 
 CREATE TABLE IF NOT EXISTS countries (
   id              INTEGER PRIMARY KEY AUTOINCREMENT,
   code            TEXT      NOT NULL UNIQUE
 );
 CREATE INDEX IF NOT EXISTS country_code ON countries(code);
+
+-- Rollups of hits and unique users per hour and day, updated with each
+-- imported batch. bucket is the UTC start of the period as a unix timestamp.
+CREATE TABLE IF NOT EXISTS rollup_requests (
+  period          TEXT      NOT NULL,
+  bucket          BIGINT    NOT NULL,
+  request_id      INTEGER   NOT NULL,
+  hits            INTEGER   NOT NULL,
+  users           INTEGER   NOT NULL,
+  PRIMARY KEY (period, bucket, request_id),
+  FOREIGN KEY (request_id) REFERENCES requests(id)
+);
+
+CREATE TABLE IF NOT EXISTS rollup_referrers (
+  period          TEXT      NOT NULL,
+  bucket          BIGINT    NOT NULL,
+  referrer_id     INTEGER   NOT NULL,
+  hits            INTEGER   NOT NULL,
+  users           INTEGER   NOT NULL,
+  PRIMARY KEY (period, bucket, referrer_id),
+  FOREIGN KEY (referrer_id) REFERENCES referrers(id)
+);
+
+CREATE TABLE IF NOT EXISTS rollup_statuses (
+  period          TEXT      NOT NULL,
+  bucket          BIGINT    NOT NULL,
+  status_code     INTEGER   NOT NULL,
+  hits            INTEGER   NOT NULL,
+  users           INTEGER   NOT NULL,
+  PRIMARY KEY (period, bucket, status_code)
+);
+
+-- browser_family is 'Other' for users without a useragent
+CREATE TABLE IF NOT EXISTS rollup_browsers (
+  period          TEXT      NOT NULL,
+  bucket          BIGINT    NOT NULL,
+  browser_family  TEXT      NOT NULL,
+  hits            INTEGER   NOT NULL,
+  users           INTEGER   NOT NULL,
+  PRIMARY KEY (period, bucket, browser_family)
+);
+
+-- HyperLogLog sketches of the user hashes, see src/hll.rs. day is the UTC
+-- start of the day as a unix timestamp. They stay usable after the hashes are
+-- pruned.
+CREATE TABLE IF NOT EXISTS sketches_daily (
+  day             BIGINT    PRIMARY KEY,
+  sketch          BLOB      NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sketches_requests (
+  day             BIGINT    NOT NULL,
+  request_id      INTEGER   NOT NULL,
+  sketch          BLOB      NOT NULL,
+  PRIMARY KEY (day, request_id),
+  FOREIGN KEY (request_id) REFERENCES requests(id)
+);
+
+CREATE TABLE IF NOT EXISTS sketches_referrers (
+  day             BIGINT    NOT NULL,
+  referrer_id     INTEGER   NOT NULL,
+  sketch          BLOB      NOT NULL,
+  PRIMARY KEY (day, referrer_id),
+  FOREIGN KEY (referrer_id) REFERENCES referrers(id)
+);
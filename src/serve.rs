@@ -0,0 +1,205 @@
+//! Read-only HTTP dashboard, the built-in reports as JSON and a static page
+//! drawing them
+
+use crate::db::open_read_only;
+use crate::filter::parse_time;
+use crate::report::{self, parse_tz_arg, OutputFormat, Period, ReportArgs, ReportKind, ReportTz};
+use clap::{Args, ValueEnum};
+use rusqlite::Connection;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Response, Server};
+
+static DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Requests are handled by this many threads, each with own connection
+static WORKERS: usize = 4;
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// SQLite database file, opened read-only
+    #[arg(long, default_value = ".cache.db")]
+    pub db: String,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+}
+
+/// Response status, content type and body
+type Reply = (u16, &'static str, String);
+
+pub fn run(args: ServeArgs) {
+    if !Path::new(&args.db).exists() {
+        panic!("Database {} doesn't exist, import something first", args.db);
+    }
+    let pool = open_read_only(&args.db).unwrap();
+    let server = Arc::new(
+        Server::http(&args.listen)
+            .unwrap_or_else(|err| panic!("Unable to listen on {}: {}", args.listen, err)),
+    );
+    println!("Serving {} on http://{}/", args.db, args.listen);
+
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let (status, content_type, body) = match request.method() {
+                        Method::Get => handle(&pool.get().unwrap(), request.url()),
+                        _ => (405, "text/plain", "Method not allowed".to_owned()),
+                    };
+                    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+                    let response = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(header);
+
+                    // Client may be gone already, nothing to do about it
+                    let _ = request.respond(response);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+/// Routes the request URL: `/` is the dashboard page and `/api/<report>` the
+/// report as JSON. Reports take the `from`, `to`, `limit`, `period`,
+/// `exclude_bots` and `exclude_internal` query parameters like the `report`
+/// command.
+fn handle(con: &Connection, url: &str) -> Reply {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    match path {
+        "/" | "/index.html" => (200, "text/html; charset=utf-8", DASHBOARD.to_owned()),
+        _ => match path
+            .strip_prefix("/api/")
+            .and_then(|kind| ReportKind::from_str(kind, true).ok())
+        {
+            Some(kind) => match report_args(kind, query) {
+                Ok(args) => report_json(con, &args),
+                Err(err) => (400, "text/plain", err),
+            },
+            None => (404, "text/plain", "Not found".to_owned()),
+        },
+    }
+}
+
+fn report_args(kind: ReportKind, query: &str) -> Result<ReportArgs, String> {
+    let mut args = ReportArgs {
+        kind,
+        db: String::new(),
+        from: None,
+        to: None,
+        limit: 20,
+        period: Period::Day,
+        tz: ReportTz::Utc,
+        exclude_bots: false,
+        exclude_internal: false,
+        format: OutputFormat::Json,
+    };
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value);
+        let invalid = || format!("invalid {} '{}'", key, value);
+        match key {
+            "from" => args.from = Some(parse_time(&value).ok_or_else(invalid)?),
+            "to" => args.to = Some(parse_time(&value).ok_or_else(invalid)?),
+            "limit" => args.limit = value.parse().map_err(|_| invalid())?,
+            "period" => args.period = Period::from_str(&value, true).map_err(|_| invalid())?,
+            "tz" => args.tz = parse_tz_arg(&value)?,
+            "exclude_bots" => args.exclude_bots = value != "0" && value != "false",
+            "exclude_internal" => args.exclude_internal = value != "0" && value != "false",
+            _ => return Err(format!("unknown parameter '{}'", key)),
+        }
+    }
+    Ok(args)
+}
+
+fn report_json(con: &Connection, args: &ReportArgs) -> Reply {
+    let table = match report::run(con, args) {
+        Ok(table) => table,
+        Err(err) => return (500, "text/plain", format!("{:?}", err)),
+    };
+    let mut body = Vec::new();
+    table.write(OutputFormat::Json, &mut body).unwrap();
+    (200, "application/json", String::from_utf8(body).unwrap())
+}
+
+/// Decodes `%XX` escapes and `+` as space, invalid escapes are kept as is
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', None) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, percent_decode};
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::importer::Dedup;
+    use crate::models::*;
+
+    #[test]
+    fn test_handle() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let entries = [0, 10, 90000]
+            .iter()
+            .map(|timestamp| LogEntry::test(*timestamp, 1, "/"))
+            .collect::<Vec<_>>();
+        batch_insert(&sender, &con, &entries, &mut caches, Dedup::Strict).unwrap();
+
+        let (status, content_type, body) = handle(&con, "/");
+        assert_eq!((200, "text/html; charset=utf-8"), (status, content_type));
+        assert!(body.contains("<html"));
+
+        let (status, content_type, body) =
+            handle(&con, "/api/top-urls?to=1970-01-02&limit=5&exclude_bots=1");
+        assert_eq!((200, "application/json"), (status, content_type));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            serde_json::json!([{"url": "/", "hits": 2, "users": 1}]),
+            json
+        );
+
+        assert_eq!(400, handle(&con, "/api/status?from=yesterday").0);
+        assert_eq!(400, handle(&con, "/api/status?foo=1").0);
+        assert_eq!(404, handle(&con, "/api/nothing").0);
+        assert_eq!(404, handle(&con, "/favicon.ico").0);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(
+            "2021-10-01T00:00:00+02:00",
+            percent_decode("2021-10-01T00%3A00%3A00%2B02:00")
+        );
+        assert_eq!("a b%zz%", percent_decode("a+b%zz%"));
+    }
+}
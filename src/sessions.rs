@@ -0,0 +1,241 @@
+use crate::db::Result;
+use rusqlite::{params, Connection};
+
+/// Assigns the entries without a session to sessions
+///
+/// A session is a run of entries of one user where consecutive entries are
+/// less than `gap` seconds apart. Existing sessions of the user near the new
+/// entries are rebuilt, so entries bridging two sessions merge them. Returns
+/// the number of sessions written.
+pub fn update_sessions(con: &Connection, gap: i64) -> Result<usize> {
+    let mut stmt = con.prepare_cached(
+        "
+            SELECT user_id, MIN(timestamp), MAX(timestamp)
+            FROM entrys
+            WHERE session_id IS NULL
+            GROUP BY user_id
+        ",
+    )?;
+    let users = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(i32, i64, i64)>, _>>()?;
+
+    let mut written = 0;
+    for (user_id, min_timestamp, max_timestamp) in users {
+        written +=
+            rebuild_user_sessions(con, user_id, min_timestamp - gap, max_timestamp + gap, gap)?;
+    }
+    Ok(written)
+}
+
+fn rebuild_user_sessions(
+    con: &Connection,
+    user_id: i32,
+    from: i64,
+    to: i64,
+    gap: i64,
+) -> Result<usize> {
+    // Sessions within the gap of the new entries are rebuilt, so the span is
+    // widened to include all of their entries
+    let mut stmt = con.prepare_cached(
+        "
+            SELECT MIN(start_timestamp), MAX(end_timestamp)
+            FROM sessions
+            WHERE user_id = ? AND end_timestamp >= ? AND start_timestamp <= ?
+        ",
+    )?;
+    let (start, end): (Option<i64>, Option<i64>) = stmt
+        .query_row(params![user_id, from, to], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    let from = start.map_or(from, |start| start.min(from));
+    let to = end.map_or(to, |end| end.max(to));
+
+    let mut stmt = con.prepare_cached(
+        "
+            UPDATE entrys SET session_id = NULL
+            WHERE user_id = ? AND timestamp BETWEEN ? AND ?
+        ",
+    )?;
+    stmt.execute(params![user_id, from, to])?;
+
+    let mut stmt = con.prepare_cached(
+        "
+            DELETE FROM sessions
+            WHERE user_id = ? AND end_timestamp >= ? AND start_timestamp <= ?
+        ",
+    )?;
+    stmt.execute(params![user_id, from, to])?;
+
+    let mut stmt = con.prepare_cached(
+        "
+            SELECT timestamp, request_id, hit_count
+            FROM entrys
+            WHERE user_id = ? AND timestamp BETWEEN ? AND ?
+            ORDER BY timestamp, id
+        ",
+    )?;
+    let entries = stmt
+        .query_map(params![user_id, from, to], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<Vec<(i64, i32, i64)>, _>>()?;
+
+    let mut written = 0;
+    let mut rest = &entries[..];
+    while !rest.is_empty() {
+        let len = rest
+            .windows(2)
+            .position(|w| w[1].0 - w[0].0 >= gap)
+            .map_or(rest.len(), |i| i + 1);
+        let (session, next) = rest.split_at(len);
+        insert_session(con, user_id, session)?;
+        written += 1;
+        rest = next;
+    }
+    Ok(written)
+}
+
+/// Inserts the session of `(timestamp, request_id, hit_count)` entries,
+/// sorted by time. Repeats counted to an entry count to the session too.
+fn insert_session(con: &Connection, user_id: i32, entries: &[(i64, i32, i64)]) -> Result<()> {
+    let (start, landing_request_id, _) = entries[0];
+    let (end, exit_request_id, _) = entries[entries.len() - 1];
+    let entry_count: i64 = entries.iter().map(|(_, _, hit_count)| hit_count).sum();
+
+    let mut stmt = con.prepare_cached(
+        "
+            INSERT INTO
+            sessions(user_id, start_timestamp, end_timestamp, entry_count, landing_request_id, exit_request_id)
+            VALUES(?, ?, ?, ?, ?, ?)
+            RETURNING id
+        ",
+    )?;
+    let session_id: i32 = stmt.query_row(
+        params![
+            user_id,
+            start,
+            end,
+            entry_count,
+            landing_request_id,
+            exit_request_id
+        ],
+        // Get the ID
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = con.prepare_cached(
+        "
+            UPDATE entrys SET session_id = ?
+            WHERE user_id = ? AND timestamp BETWEEN ? AND ?
+        ",
+    )?;
+    stmt.execute(params![session_id, user_id, start, end])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::update_sessions;
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::importer::Dedup;
+    use crate::models::*;
+    use itertools::Itertools;
+    use rusqlite::Connection;
+
+    fn entries(timestamps: &[i64]) -> Vec<LogEntry<'static>> {
+        timestamps
+            .iter()
+            .map(|timestamp| LogEntry::test(*timestamp, 123, &format!("/page/{}", timestamp)))
+            .collect()
+    }
+
+    fn sessions(con: &Connection) -> Vec<(i64, i64, i64, String, String)> {
+        let mut stmt = con
+            .prepare(
+                "
+                SELECT s.start_timestamp, s.end_timestamp, s.entry_count, l.url, e.url
+                FROM sessions s, requests l, requests e
+                WHERE s.landing_request_id = l.id AND s.exit_request_id = e.id
+                ORDER BY s.start_timestamp
+                ",
+            )
+            .unwrap();
+        stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })
+        .unwrap()
+        .flatten()
+        .collect_vec()
+    }
+
+    #[test]
+    fn test_update_sessions() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+
+        batch_insert(
+            &sender,
+            &con,
+            &entries(&[0, 100, 5000]),
+            &mut caches,
+            Dedup::Strict,
+        )
+        .unwrap();
+        assert_eq!(2, update_sessions(&con, 1800).unwrap());
+        assert_eq!(
+            vec![
+                (0, 100, 2, "/page/0".to_owned(), "/page/100".to_owned()),
+                (
+                    5000,
+                    5000,
+                    1,
+                    "/page/5000".to_owned(),
+                    "/page/5000".to_owned()
+                )
+            ],
+            sessions(&con)
+        );
+
+        // Nothing new, nothing to do
+        assert_eq!(0, update_sessions(&con, 1800).unwrap());
+
+        // Bridges the two sessions
+        batch_insert(
+            &sender,
+            &con,
+            &entries(&[1500, 2900, 4200]),
+            &mut caches,
+            Dedup::Strict,
+        )
+        .unwrap();
+        assert_eq!(1, update_sessions(&con, 1800).unwrap());
+        assert_eq!(
+            vec![(0, 5000, 6, "/page/0".to_owned(), "/page/5000".to_owned())],
+            sessions(&con)
+        );
+    }
+
+    #[test]
+    fn test_session_hit_count() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+
+        let mut repeated = entries(&[0, 100]);
+        repeated[1].hits = 3;
+        batch_insert(&sender, &con, &repeated, &mut caches, Dedup::Count).unwrap();
+        assert_eq!(1, update_sessions(&con, 1800).unwrap());
+        assert_eq!(
+            vec![(0, 100, 4, "/page/0".to_owned(), "/page/100".to_owned())],
+            sessions(&con)
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! HyperLogLog sketches of the users per day, per day and request, and per
+//! day and referrer
+//!
+//! Sketches are built from the user hashes at import time and stored as blobs,
+//! so distinct users over any range of days can still be estimated after the
+//! hashes are pruned.
+
+use crate::db::{table_exists, BatchCache, Result};
+use crate::hll::HyperLogLog;
+use crate::models::LogEntry;
+use rusqlite::functions::{Aggregate, Context, FunctionFlags};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use std::collections::HashMap;
+
+pub const DAY: i64 = 24 * 3600;
+
+/// Adds the users of the entries to the stored sketches. Entries must be
+/// inserted first, so their ids are in the caches. Duplicate entries don't
+/// change the sketches.
+pub fn update_sketches(con: &Connection, entries: &[LogEntry], caches: &BatchCache) -> Result<()> {
+    let users = entries.iter().filter_map(|entry| {
+        Some(SketchedUser {
+            timestamp: entry.timestamp,
+            hash: entry.user.hash?,
+            request_id: caches.requests_cache.get(&entry.request).copied(),
+            referrer_id: entry
+                .referrer
+                .as_ref()
+                .and_then(|r| caches.referrer_cache.get(r))
+                .copied(),
+        })
+    });
+    add_users(con, users)
+}
+
+/// Builds the sketches of the entries imported before the sketches were kept,
+/// called once when the tables are created for an existing database
+pub fn backfill_sketches(con: &Connection) -> Result<()> {
+    let mut stmt = con.prepare(
+        "
+            SELECT e.timestamp, u.hash, e.request_id, e.referrer_id
+            FROM entrys e JOIN users u ON e.user_id = u.id
+            WHERE u.hash IS NOT NULL
+        ",
+    )?;
+    let users = stmt
+        .query_map([], |row| {
+            Ok(SketchedUser {
+                timestamp: row.get(0)?,
+                hash: row.get(1)?,
+                request_id: row.get(2)?,
+                referrer_id: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    add_users(con, users)
+}
+
+/// Stores the sketches of earlier versions, which were all dense, sparse when
+/// that is smaller
+pub fn compact_sketches(con: &Connection) -> Result<()> {
+    con.create_scalar_function(
+        "hll_compact",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let sketch = ctx.get_raw(0).as_blob()?;
+            Ok(HyperLogLog::from_bytes(sketch)
+                .map_or_else(|| sketch.to_vec(), |hll| hll.to_bytes()))
+        },
+    )?;
+    for table in ["sketches_daily", "sketches_requests", "sketches_referrers"] {
+        if table_exists(con, table)? {
+            con.execute(
+                &format!("UPDATE {} SET sketch = hll_compact(sketch)", table),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+struct SketchedUser {
+    timestamp: i64,
+    hash: i64,
+    request_id: Option<i32>,
+    referrer_id: Option<i32>,
+}
+
+fn add_users(con: &Connection, users: impl IntoIterator<Item = SketchedUser>) -> Result<()> {
+    let mut days: HashMap<i64, HyperLogLog> = HashMap::new();
+    let mut requests: HashMap<(i64, i32), HyperLogLog> = HashMap::new();
+    let mut referrers: HashMap<(i64, i32), HyperLogLog> = HashMap::new();
+
+    for user in users {
+        let day = user.timestamp.div_euclid(DAY) * DAY;
+        days.entry(day).or_default().insert(user.hash);
+        if let Some(request_id) = user.request_id {
+            requests
+                .entry((day, request_id))
+                .or_default()
+                .insert(user.hash);
+        }
+        if let Some(referrer_id) = user.referrer_id {
+            referrers
+                .entry((day, referrer_id))
+                .or_default()
+                .insert(user.hash);
+        }
+    }
+
+    for (day, hll) in days {
+        merge_sketch(
+            con,
+            "SELECT sketch FROM sketches_daily WHERE day = ?",
+            "INSERT OR REPLACE INTO sketches_daily(day, sketch) VALUES(?, ?)",
+            params![day],
+            hll,
+        )?;
+    }
+    for ((day, request_id), hll) in requests {
+        merge_sketch(
+            con,
+            "SELECT sketch FROM sketches_requests WHERE day = ? AND request_id = ?",
+            "INSERT OR REPLACE INTO sketches_requests(day, request_id, sketch) VALUES(?, ?, ?)",
+            params![day, request_id],
+            hll,
+        )?;
+    }
+    for ((day, referrer_id), hll) in referrers {
+        merge_sketch(
+            con,
+            "SELECT sketch FROM sketches_referrers WHERE day = ? AND referrer_id = ?",
+            "INSERT OR REPLACE INTO sketches_referrers(day, referrer_id, sketch) VALUES(?, ?, ?)",
+            params![day, referrer_id],
+            hll,
+        )?;
+    }
+    Ok(())
+}
+
+/// Merges the sketch with the stored one. `insert` takes the `key` params
+/// followed by the sketch.
+fn merge_sketch(
+    con: &Connection,
+    select: &str,
+    insert: &str,
+    key: &[&dyn ToSql],
+    mut hll: HyperLogLog,
+) -> Result<()> {
+    let mut stmt = con.prepare_cached(select)?;
+    let stored: Option<Vec<u8>> = stmt.query_row(key, |row| row.get(0)).optional()?;
+    if let Some(stored) = stored.as_deref().and_then(HyperLogLog::from_bytes) {
+        hll.merge(&stored);
+    }
+
+    let sketch = hll.to_bytes();
+    let mut values = key.to_vec();
+    values.push(&sketch);
+    let mut stmt = con.prepare_cached(insert)?;
+    stmt.execute(&values[..])?;
+    Ok(())
+}
+
+/// Merged sketch of the days starting within `from..to`, and the number of
+/// days found
+pub fn users_between(con: &Connection, from: i64, to: i64) -> Result<(HyperLogLog, usize)> {
+    let mut stmt = con.prepare_cached(
+        "
+            SELECT sketch FROM sketches_daily WHERE day >= ? AND day < ?
+        ",
+    )?;
+    let sketches = stmt
+        .query_map(params![from, to], |row| row.get(0))?
+        .collect::<Result<Vec<Vec<u8>>, _>>()?;
+
+    let mut hll = HyperLogLog::new();
+    for sketch in sketches.iter().filter_map(|s| HyperLogLog::from_bytes(s)) {
+        hll.merge(&sketch);
+    }
+    Ok((hll, sketches.len()))
+}
+
+/// Registers the aggregate `hll_count(sketch)`, the estimated users of the
+/// merged sketches
+pub fn register_hll_count(con: &Connection) -> Result<()> {
+    con.create_aggregate_function(
+        "hll_count",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        HllCount,
+    )?;
+    Ok(())
+}
+
+struct HllCount;
+
+impl Aggregate<HyperLogLog, i64> for HllCount {
+    fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<HyperLogLog> {
+        Ok(HyperLogLog::new())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, hll: &mut HyperLogLog) -> rusqlite::Result<()> {
+        if let Some(sketch) = HyperLogLog::from_bytes(ctx.get_raw(0).as_blob()?) {
+            hll.merge(&sketch);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, hll: Option<HyperLogLog>) -> rusqlite::Result<i64> {
+        Ok(hll.map_or(0, |hll| hll.count() as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compact_sketches, users_between};
+    use crate::db::{batch_insert, init, BatchCache};
+    use crate::hll::HyperLogLog;
+    use crate::importer::Dedup;
+    use crate::models::*;
+
+    fn entry(timestamp: i64, hash: i64) -> LogEntry<'static> {
+        let mut entry = LogEntry::test(timestamp, hash, "/");
+        entry.referrer = Some(Referrer {
+            url: "https://example.com/".to_owned().into(),
+        });
+        entry
+    }
+
+    #[test]
+    fn test_update_sketches() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        let mut caches = BatchCache::new();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+
+        // Users 0..100 on the first day, 50..150 on the second day in two
+        // batches
+        let day = 24 * 3600;
+        let first = (0..100).map(|h| entry(h, h)).collect::<Vec<_>>();
+        let second = (50..150).map(|h| entry(day + h, h)).collect::<Vec<_>>();
+        batch_insert(&sender, &con, &first, &mut caches, Dedup::Strict).unwrap();
+        batch_insert(&sender, &con, &second[..50], &mut caches, Dedup::Strict).unwrap();
+        batch_insert(&sender, &con, &second[50..], &mut caches, Dedup::Strict).unwrap();
+
+        // Hashes are gone, sketches remain
+        con.execute("UPDATE users SET hash = NULL", []).unwrap();
+
+        // Counts are approximate, small ones within a couple of users
+        let (hll, days) = users_between(&con, 0, day).unwrap();
+        assert_eq!(1, days);
+        assert!((98..=102).contains(&hll.count()));
+        let (hll, days) = users_between(&con, 0, 2 * day).unwrap();
+        assert_eq!(2, days);
+        assert!((147..=153).contains(&hll.count()));
+
+        let count = |table: &str| -> i64 {
+            con.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(2, count("sketches_requests"));
+        assert_eq!(2, count("sketches_referrers"));
+    }
+
+    #[test]
+    fn test_compact_sketches() {
+        let con = init(":memory:").unwrap().get().unwrap();
+        // Dense sketch of an earlier version with two registers set
+        let mut dense = vec![0u8; 4096];
+        dense[7] = 2;
+        dense[4000] = 1;
+        con.execute(
+            "INSERT INTO sketches_daily(day, sketch) VALUES(0, ?)",
+            [&dense],
+        )
+        .unwrap();
+
+        compact_sketches(&con).unwrap();
+        let sketch: Vec<u8> = con
+            .query_row("SELECT sketch FROM sketches_daily", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(vec![0, 7, 2, 15, 160, 1], sketch);
+        assert_eq!(
+            HyperLogLog::from_bytes(&dense).unwrap().count(),
+            HyperLogLog::from_bytes(&sketch).unwrap().count()
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use super::Store;
+use crate::db::{DbError, Result};
+use crate::importer::{Dedup, Msg};
+use crate::models::{LogEntry, Request, User};
+use crate::referrers::parse_referrer;
+use itertools::Itertools;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Keeps the entries in memory, for tests and trying out the import. Entries
+/// are unique by time with the sub-second part, line number, request and
+/// user like in the SQLite schema.
+#[derive(Default, Debug)]
+pub struct MemoryStore {
+    pub entries: Vec<LogEntry<'static>>,
+    pub bots: HashSet<User<'static>>,
+    /// Lowercase hosts of the referrers from the own hosts
+    pub internal_hosts: HashSet<String>,
+    /// Number of sessions after the last import
+    pub sessions: usize,
+    /// Index of the entry with the key
+    keys: HashMap<(i64, i32, i32, i64, Request<'static>, User<'static>), usize>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn populate(&mut self, _msg_sender: &crossbeam_channel::Sender<Msg>) -> Result<()> {
+        Ok(())
+    }
+
+    fn insert_batch(
+        &mut self,
+        msg_sender: &crossbeam_channel::Sender<Msg>,
+        entries: &[LogEntry],
+        dedup: Dedup,
+    ) -> Result<()> {
+        for entry in entries {
+            let entry = entry.clone().into_owned();
+            let key = (
+                entry.timestamp,
+                entry.micros,
+                entry.source,
+                entry.line_number,
+                entry.request.clone(),
+                entry.user.clone(),
+            );
+            match self.keys.entry(key) {
+                Entry::Vacant(vacant) => {
+                    vacant.insert(self.entries.len());
+                    self.entries.push(entry);
+                    msg_sender.send(Msg::RowInserted).unwrap();
+                }
+                Entry::Occupied(first) if dedup == Dedup::Count => {
+                    self.entries[*first.get()].hits += entry.hits;
+                    msg_sender.send(Msg::RowCounted).unwrap();
+                }
+                Entry::Occupied(_) => msg_sender.send(DbError::DuplicateEntry.into()).unwrap(),
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_bots(&mut self, users: &[User]) -> Result<()> {
+        self.bots
+            .extend(users.iter().map(|u| u.clone().into_owned()));
+        Ok(())
+    }
+
+    fn finish(&mut self, own_hosts: &[String], session_gap: i64) -> Result<usize> {
+        let own_hosts = own_hosts.iter().map(|h| h.to_lowercase()).collect_vec();
+        self.internal_hosts = self
+            .entries
+            .iter()
+            .filter_map(|e| e.referrer.as_ref())
+            .filter_map(|r| parse_referrer(&r.url).host)
+            .filter(|host| {
+                own_hosts
+                    .iter()
+                    .any(|own| host == own || host.ends_with(&format!(".{}", own)))
+            })
+            .collect();
+
+        // Sessions are recounted from all entries
+        self.sessions = self
+            .entries
+            .iter()
+            .into_group_map_by(|e| &e.user)
+            .into_values()
+            .map(|entries| {
+                let timestamps = entries.iter().map(|e| e.timestamp).sorted().collect_vec();
+                1 + timestamps
+                    .windows(2)
+                    .filter(|w| w[1] - w[0] >= session_gap)
+                    .count()
+            })
+            .sum();
+        Ok(self.sessions)
+    }
+}
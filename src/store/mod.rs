@@ -0,0 +1,67 @@
+//! Storage backends of the import
+//!
+//! A [`Store`] keeps its own dimension caches and inserts the parsed entries.
+//! [`SqliteStore`] is the default, the reports, export and dashboard work
+//! only with it.
+
+use crate::db::Result;
+use crate::importer::{Dedup, Msg};
+use crate::models::{LogEntry, User};
+use crossbeam_channel::Sender;
+
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStore;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+pub trait Store: Send {
+    /// Loads the existing dimensions to the caches, called once before the
+    /// first batch
+    fn populate(&mut self, msg_sender: &Sender<Msg>) -> Result<()>;
+
+    /// Inserts the entries of one chunk, sorted by time. Sends
+    /// `Msg::RowInserted` or the error of each entry, duplicates are
+    /// `DbError::DuplicateEntry`, or `Msg::RowCounted` with `Dedup::Count`.
+    fn insert_batch(
+        &mut self,
+        msg_sender: &Sender<Msg>,
+        entries: &[LogEntry],
+        dedup: Dedup,
+    ) -> Result<()>;
+
+    /// Marks already inserted users as bots
+    fn mark_bots(&mut self, users: &[User]) -> Result<()>;
+
+    /// Called once after all batches, flags the referrers from the own hosts
+    /// as internal and updates the sessions. Returns the number of sessions
+    /// written.
+    fn finish(&mut self, own_hosts: &[String], session_gap: i64) -> Result<usize>;
+}
+
+impl<S: Store + ?Sized> Store for &mut S {
+    fn populate(&mut self, msg_sender: &Sender<Msg>) -> Result<()> {
+        (**self).populate(msg_sender)
+    }
+
+    fn insert_batch(
+        &mut self,
+        msg_sender: &Sender<Msg>,
+        entries: &[LogEntry],
+        dedup: Dedup,
+    ) -> Result<()> {
+        (**self).insert_batch(msg_sender, entries, dedup)
+    }
+
+    fn mark_bots(&mut self, users: &[User]) -> Result<()> {
+        (**self).mark_bots(users)
+    }
+
+    fn finish(&mut self, own_hosts: &[String], session_gap: i64) -> Result<usize> {
+        (**self).finish(own_hosts, session_gap)
+    }
+}
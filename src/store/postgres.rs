@@ -0,0 +1,506 @@
+use super::Store;
+use crate::db::{BatchCache, DbError, Result};
+use crate::importer::{Dedup, Msg};
+use crate::models::{Country, LogEntry, Referrer, Request, User, Useragent};
+use crate::referrers::{classify_host, parse_referrer};
+use crate::useragent::classify;
+use crossbeam_channel::Sender;
+use postgres::{Client, NoTls, Statement, Transaction};
+
+const SCHEMA: &str = include_str!("schema_postgres.sql");
+
+/// PostgreSQL database, see `schema_postgres.sql`. Entries and dimensions are
+/// stored like in SQLite, but rollups, sketches and sessions are not
+/// maintained, so `finish` always returns zero sessions.
+pub struct PostgresStore {
+    client: Client,
+    cache: BatchCache,
+    statements: Statements,
+}
+
+/// Prepared once per connection
+struct Statements {
+    insert_request: Statement,
+    insert_useragent: Statement,
+    insert_useragent_details: Statement,
+    insert_country: Statement,
+    insert_user: Statement,
+    insert_referrer_domain: Statement,
+    insert_referrer: Statement,
+    insert_entry: Statement,
+    count_repeat: Statement,
+}
+
+impl PostgresStore {
+    /// Connects with the connection string, e.g.
+    /// `host=localhost user=postgres dbname=logs` or a `postgresql://` URL
+    pub fn connect(params: &str) -> Result<Self> {
+        Self::new(Client::connect(params, NoTls)?)
+    }
+
+    /// Creates the schema if needed
+    pub fn new(mut client: Client) -> Result<Self> {
+        client.batch_execute(SCHEMA)?;
+        let statements = Statements {
+            insert_request: client.prepare(
+                "
+                INSERT INTO requests(method, url, status_code)
+                VALUES($1, $2, $3)
+                RETURNING id
+                ",
+            )?,
+            insert_useragent: client.prepare(
+                "
+                INSERT INTO useragents(value)
+                VALUES($1)
+                RETURNING id
+                ",
+            )?,
+            insert_useragent_details: client.prepare(
+                "
+                INSERT INTO
+                useragent_details(useragent_id, browser_family, browser_major, os_family, device_type, is_bot)
+                VALUES($1, $2, $3, $4, $5, $6)
+                ",
+            )?,
+            insert_country: client.prepare(
+                "
+                INSERT INTO countries(code)
+                VALUES($1)
+                RETURNING id
+                ",
+            )?,
+            insert_user: client.prepare(
+                "
+                INSERT INTO users(hash, useragent_id, country_id)
+                VALUES($1, $2, $3)
+                ON CONFLICT ON CONSTRAINT users_hash
+                DO UPDATE SET country_id = COALESCE(users.country_id, EXCLUDED.country_id)
+                RETURNING id
+                ",
+            )?,
+            insert_referrer_domain: client.prepare(
+                "
+                INSERT INTO referrer_domains(host, source_kind, source_name)
+                VALUES($1, $2, $3)
+                RETURNING id
+                ",
+            )?,
+            insert_referrer: client.prepare(
+                "
+                INSERT INTO referrers(url, scheme, domain_id, path)
+                VALUES($1, $2, $3, $4)
+                RETURNING id
+                ",
+            )?,
+            // Unique violation would abort the transaction, so duplicates
+            // are skipped and detected from the row count
+            insert_entry: client.prepare(
+                "
+                INSERT INTO entrys(timestamp, micros, utc_offset, line_number, source,
+                    hit_count, request_id, user_id, referrer_id)
+                VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT DO NOTHING
+                ",
+            )?,
+            count_repeat: client.prepare(
+                "
+                UPDATE entrys SET hit_count = hit_count + $1
+                WHERE timestamp = $2 AND micros = $3 AND source = $4 AND line_number = $5
+                    AND request_id = $6 AND user_id = $7
+                ",
+            )?,
+        };
+        Ok(PostgresStore {
+            client,
+            cache: BatchCache::new(),
+            statements,
+        })
+    }
+}
+
+impl Store for PostgresStore {
+    fn populate(&mut self, _msg_sender: &Sender<Msg>) -> Result<()> {
+        let cache = &mut self.cache;
+        for row in self
+            .client
+            .query("SELECT id, method, url, status_code FROM requests", &[])?
+        {
+            let request = Request {
+                method: row.try_get::<_, String>(1)?.into(),
+                url: row.try_get::<_, String>(2)?.into(),
+                status_code: row.try_get(3)?,
+            };
+            cache.requests_cache.insert(request, row.try_get(0)?);
+        }
+        for row in self.client.query(
+            "
+            SELECT u.id, u.hash, ua.value, c.code
+            FROM users u
+            LEFT JOIN useragents ua ON u.useragent_id = ua.id
+            LEFT JOIN countries c ON u.country_id = c.id
+            ",
+            &[],
+        )? {
+            let useragent: Option<String> = row.try_get(2)?;
+            let country: Option<String> = row.try_get(3)?;
+            let user = User {
+                hash: row.try_get(1)?,
+                useragent: useragent.map(|value| Useragent {
+                    value: value.into(),
+                }),
+                country: country.map(|code| Country { code }),
+            };
+            cache.users_cache.insert(user, row.try_get(0)?);
+        }
+        for row in self.client.query("SELECT id, value FROM useragents", &[])? {
+            let useragent = Useragent {
+                value: row.try_get::<_, String>(1)?.into(),
+            };
+            cache.useragents_cache.insert(useragent, row.try_get(0)?);
+        }
+        for row in self.client.query("SELECT id, url FROM referrers", &[])? {
+            let referrer = Referrer {
+                url: row.try_get::<_, String>(1)?.into(),
+            };
+            cache.referrer_cache.insert(referrer, row.try_get(0)?);
+        }
+        for row in self.client.query("SELECT id, code FROM countries", &[])? {
+            let country = Country {
+                code: row.try_get(1)?,
+            };
+            cache.countries_cache.insert(country, row.try_get(0)?);
+        }
+        for row in self
+            .client
+            .query("SELECT id, host FROM referrer_domains", &[])?
+        {
+            cache
+                .referrer_domains_cache
+                .insert(row.try_get(1)?, row.try_get(0)?);
+        }
+        Ok(())
+    }
+
+    fn insert_batch(
+        &mut self,
+        msg_sender: &Sender<Msg>,
+        entries: &[LogEntry],
+        dedup: Dedup,
+    ) -> Result<()> {
+        let mut tx = self.client.transaction()?;
+        let mut inserter = Inserter {
+            tx: &mut tx,
+            cache: &mut self.cache,
+            statements: &self.statements,
+        };
+        for entry in entries {
+            match inserter.insert_entry(entry, dedup) {
+                Ok(msg) => msg_sender.send(msg).unwrap(),
+                Err(err) => msg_sender.send(err.into()).unwrap(),
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn mark_bots(&mut self, users: &[User]) -> Result<()> {
+        let user_ids = users
+            .iter()
+            .filter_map(|u| self.cache.users_cache.get(u).copied())
+            .collect::<Vec<i32>>();
+        self.client.execute(
+            "UPDATE users SET is_bot = TRUE WHERE id = ANY($1)",
+            &[&user_ids],
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self, own_hosts: &[String], _session_gap: i64) -> Result<usize> {
+        if !own_hosts.is_empty() {
+            let own_hosts = own_hosts
+                .iter()
+                .map(|h| h.to_lowercase())
+                .collect::<Vec<_>>();
+            self.client.execute(
+                "
+                UPDATE referrer_domains SET is_internal = EXISTS (
+                    SELECT 1 FROM unnest($1::TEXT[]) own
+                    WHERE host = own OR host LIKE '%.' || own
+                )
+                ",
+                &[&own_hosts],
+            )?;
+        }
+        Ok(0)
+    }
+}
+
+/// Dimension lookups and inserts within one batch transaction
+struct Inserter<'a, 't> {
+    tx: &'a mut Transaction<'t>,
+    cache: &'a mut BatchCache,
+    statements: &'a Statements,
+}
+
+impl Inserter<'_, '_> {
+    fn insert_request(&mut self, request: &Request) -> Result<i32> {
+        if let Some(request_id) = self.cache.requests_cache.get(request) {
+            return Ok(*request_id);
+        }
+        let request_id = self
+            .tx
+            .query_one(
+                &self.statements.insert_request,
+                &[&request.method, &request.url, &request.status_code],
+            )?
+            .try_get(0)?;
+        self.cache
+            .requests_cache
+            .insert(request.clone().into_owned(), request_id);
+        Ok(request_id)
+    }
+
+    fn insert_useragent(&mut self, useragent: &Useragent) -> Result<i32> {
+        if let Some(useragent_id) = self.cache.useragents_cache.get(useragent) {
+            return Ok(*useragent_id);
+        }
+        let useragent_id: i32 = self
+            .tx
+            .query_one(&self.statements.insert_useragent, &[&useragent.value])?
+            .try_get(0)?;
+        let details = classify(&useragent.value);
+        self.tx.execute(
+            &self.statements.insert_useragent_details,
+            &[
+                &useragent_id,
+                &details.browser_family,
+                &details.browser_major,
+                &details.os_family,
+                &details.device_type.as_str(),
+                &details.is_bot,
+            ],
+        )?;
+        self.cache
+            .useragents_cache
+            .insert(useragent.clone().into_owned(), useragent_id);
+        Ok(useragent_id)
+    }
+
+    fn insert_country(&mut self, country: &Country) -> Result<i32> {
+        if let Some(country_id) = self.cache.countries_cache.get(country) {
+            return Ok(*country_id);
+        }
+        let country_id = self
+            .tx
+            .query_one(&self.statements.insert_country, &[&country.code])?
+            .try_get(0)?;
+        self.cache
+            .countries_cache
+            .insert(country.to_owned(), country_id);
+        Ok(country_id)
+    }
+
+    fn insert_user(&mut self, user: &User) -> Result<i32> {
+        if let Some(user_id) = self.cache.users_cache.get(user) {
+            return Ok(*user_id);
+        }
+        let useragent_id = user
+            .useragent
+            .as_ref()
+            .map(|ua| self.insert_useragent(ua))
+            .transpose()?;
+        let country_id = user
+            .country
+            .as_ref()
+            .map(|c| self.insert_country(c))
+            .transpose()?;
+        let user_id = self
+            .tx
+            .query_one(
+                &self.statements.insert_user,
+                &[&user.hash, &useragent_id, &country_id],
+            )?
+            .try_get(0)?;
+        self.cache
+            .users_cache
+            .insert(user.clone().into_owned(), user_id);
+        Ok(user_id)
+    }
+
+    fn insert_referrer_domain(&mut self, host: &str) -> Result<i32> {
+        if let Some(domain_id) = self.cache.referrer_domains_cache.get(host) {
+            return Ok(*domain_id);
+        }
+        let source = classify_host(host);
+        let domain_id = self
+            .tx
+            .query_one(
+                &self.statements.insert_referrer_domain,
+                &[
+                    &host,
+                    &source.map(|(kind, _)| kind.as_str()),
+                    &source.map(|(_, name)| name),
+                ],
+            )?
+            .try_get(0)?;
+        self.cache
+            .referrer_domains_cache
+            .insert(host.to_owned(), domain_id);
+        Ok(domain_id)
+    }
+
+    fn insert_referrer(&mut self, referrer: &Referrer) -> Result<i32> {
+        if let Some(referrer_id) = self.cache.referrer_cache.get(referrer) {
+            return Ok(*referrer_id);
+        }
+        let parts = parse_referrer(&referrer.url);
+        let domain_id = parts
+            .host
+            .as_deref()
+            .map(|host| self.insert_referrer_domain(host))
+            .transpose()?;
+        let referrer_id = self
+            .tx
+            .query_one(
+                &self.statements.insert_referrer,
+                &[&referrer.url, &parts.scheme, &domain_id, &parts.path],
+            )?
+            .try_get(0)?;
+        self.cache
+            .referrer_cache
+            .insert(referrer.clone().into_owned(), referrer_id);
+        Ok(referrer_id)
+    }
+
+    fn insert_entry(&mut self, entry: &LogEntry, dedup: Dedup) -> Result<Msg> {
+        let request_id = self.insert_request(&entry.request)?;
+        let user_id = self.insert_user(&entry.user)?;
+        let referrer_id = entry
+            .referrer
+            .as_ref()
+            .map(|r| self.insert_referrer(r))
+            .transpose()?;
+        let inserted = self.tx.execute(
+            &self.statements.insert_entry,
+            &[
+                &entry.timestamp,
+                &entry.micros,
+                &entry.utc_offset,
+                &entry.line_number,
+                &entry.source,
+                &entry.hits,
+                &request_id,
+                &user_id,
+                &referrer_id,
+            ],
+        )?;
+        match (inserted, dedup) {
+            (0, Dedup::Count) => {
+                self.tx.execute(
+                    &self.statements.count_repeat,
+                    &[
+                        &entry.hits,
+                        &entry.timestamp,
+                        &entry.micros,
+                        &entry.source,
+                        &entry.line_number,
+                        &request_id,
+                        &user_id,
+                    ],
+                )?;
+                Ok(Msg::RowCounted)
+            }
+            (0, _) => Err(DbError::DuplicateEntry),
+            _ => Ok(Msg::RowInserted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostgresStore;
+    use crate::geoip::GeoIp;
+    use crate::store::Store;
+    use crate::Importer;
+    use crate::Msg;
+    use postgres::{Client, NoTls};
+
+    /// Runs against the database of `LOGGERSON_TEST_POSTGRES` connection
+    /// string, in the `loggerson_test` schema which is dropped first
+    #[test]
+    #[ignore = "needs a PostgreSQL server, see LOGGERSON_TEST_POSTGRES"]
+    fn test_postgres_store() {
+        let params = std::env::var("LOGGERSON_TEST_POSTGRES")
+            .expect("LOGGERSON_TEST_POSTGRES set to a connection string");
+        let mut client = Client::connect(&params, NoTls).unwrap();
+        client
+            .batch_execute(
+                "
+                DROP SCHEMA IF EXISTS loggerson_test CASCADE;
+                CREATE SCHEMA loggerson_test;
+                SET search_path TO loggerson_test;
+                ",
+            )
+            .unwrap();
+        let mut store = PostgresStore::new(client).unwrap();
+
+        let lines = [
+            r#"1.2.3.4 - - [10/Oct/2021:13:55:36 +0000] "GET / HTTP/1.1" 200 10 "https://www.google.com/" "Mozilla/5.0""#,
+            r#"1.2.3.4 - - [10/Oct/2021:13:56:36 +0000] "GET /a HTTP/1.1" 200 10 "https://www.example.com/" "curl/7.68.0""#,
+        ];
+        let run = |store: &mut PostgresStore, geoip: Option<GeoIp>| {
+            let mut counts = (0, 0);
+            let mut importer = Importer::with_store(store).own_host("example.com");
+            if let Some(geoip) = geoip {
+                importer = importer.geoip(geoip);
+            }
+            importer
+                .run(lines.iter().map(|l| Ok(l.to_string())), |msg| match msg {
+                    Msg::RowInserted => counts.0 += 1,
+                    Msg::DbError(_) => counts.1 += 1,
+                    _ => {}
+                })
+                .unwrap();
+            counts
+        };
+        assert_eq!((2, 0), run(&mut store, None));
+        // Everything is a duplicate the second time, with the caches
+        // populated from the database, also when the users now have a
+        // country
+        let mut store = PostgresStore::new(store.client).unwrap();
+        store.populate(&crossbeam_channel::unbounded().0).unwrap();
+        let geoip = GeoIp::from_csv("1.0.0.0,1.255.255.255,FI".as_bytes()).unwrap();
+        assert_eq!((0, 2), run(&mut store, Some(geoip)));
+
+        let row = store
+            .client
+            .query_one(
+                "
+                SELECT
+                    (SELECT COUNT(*) FROM entrys),
+                    (SELECT COUNT(*) FROM users WHERE is_bot),
+                    (SELECT string_agg(host, ',') FROM referrer_domains WHERE is_internal),
+                    (SELECT source_name FROM referrer_domains WHERE source_kind = 'search'),
+                    (SELECT COUNT(*) FROM users WHERE country_id IS NOT NULL)
+                ",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(2, row.get::<_, i64>(0));
+        assert_eq!(1, row.get::<_, i64>(1));
+        assert_eq!("www.example.com", row.get::<_, String>(2));
+        assert_eq!("Google", row.get::<_, String>(3));
+        assert_eq!(2, row.get::<_, i64>(4));
+        // Unique by the hash also with another country
+        let duplicate = store.client.execute(
+            "INSERT INTO users(hash, useragent_id, country_id) SELECT hash, useragent_id, 1 FROM users",
+            &[],
+        );
+        assert!(duplicate.is_err());
+        store
+            .client
+            .batch_execute("DROP SCHEMA loggerson_test CASCADE")
+            .unwrap();
+    }
+}
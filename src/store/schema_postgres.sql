@@ -0,0 +1,126 @@
+-- Same tables as schema.sql for the imported entries and dimensions. Rollups,
+-- sketches and sessions are only maintained in SQLite.
+
+CREATE TABLE IF NOT EXISTS requests (
+  id              SERIAL    PRIMARY KEY,
+  method          TEXT      NOT NULL,
+  url             TEXT      NOT NULL,
+  status_code     INTEGER   NOT NULL,
+  UNIQUE (method, url, status_code)
+);
+
+CREATE TABLE IF NOT EXISTS useragents (
+  id              SERIAL    PRIMARY KEY,
+  value           TEXT      NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS useragent_details (
+  useragent_id    INTEGER   PRIMARY KEY REFERENCES useragents(id),
+  browser_family  TEXT      NOT NULL,
+  browser_major   TEXT,
+  os_family       TEXT      NOT NULL,
+  device_type     TEXT      NOT NULL,
+  is_bot          BOOLEAN   NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS countries (
+  id              SERIAL    PRIMARY KEY,
+  code            TEXT      NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS users (
+  id              SERIAL    PRIMARY KEY,
+  hash            BIGINT,
+  useragent_id    INTEGER   REFERENCES useragents(id),
+  country_id      INTEGER   REFERENCES countries(id),
+  is_bot          BOOLEAN   NOT NULL DEFAULT FALSE,
+  -- the hash is the user, NULL when forgotten
+  CONSTRAINT users_hash UNIQUE (hash)
+);
+
+CREATE TABLE IF NOT EXISTS referrer_domains (
+  id              SERIAL    PRIMARY KEY,
+  host            TEXT      NOT NULL UNIQUE,
+  source_kind     TEXT,
+  source_name     TEXT,
+  is_internal     BOOLEAN   NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS referrers (
+  id              SERIAL    PRIMARY KEY,
+  url             TEXT      NOT NULL UNIQUE,
+  scheme          TEXT,
+  domain_id       INTEGER   REFERENCES referrer_domains(id),
+  path            TEXT
+);
+
+CREATE TABLE IF NOT EXISTS entrys (
+  id              BIGSERIAL PRIMARY KEY,
+  timestamp       BIGINT    NOT NULL,
+  micros          INTEGER   NOT NULL DEFAULT 0,
+  utc_offset      INTEGER   NOT NULL DEFAULT 0,
+  line_number     BIGINT    NOT NULL DEFAULT 0,
+  source          INTEGER   NOT NULL DEFAULT 0,
+  hit_count       INTEGER   NOT NULL DEFAULT 1,
+  request_id      INTEGER   NOT NULL REFERENCES requests(id),
+  user_id         INTEGER   NOT NULL REFERENCES users(id),
+  referrer_id     INTEGER   REFERENCES referrers(id),
+  CONSTRAINT entrys_unique UNIQUE (timestamp, micros, source, line_number, request_id, user_id)
+);
+
+-- Databases of earlier versions lack the columns added since, and have an
+-- unnamed unique key without them
+ALTER TABLE entrys ADD COLUMN IF NOT EXISTS micros INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE entrys ADD COLUMN IF NOT EXISTS utc_offset INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE entrys ADD COLUMN IF NOT EXISTS line_number BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE entrys ADD COLUMN IF NOT EXISTS source INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE entrys ADD COLUMN IF NOT EXISTS hit_count INTEGER NOT NULL DEFAULT 1;
+DO $$
+DECLARE
+  old_key TEXT;
+BEGIN
+  IF to_regclass('entrys_unique') IS NULL THEN
+    FOR old_key IN
+      SELECT conname FROM pg_constraint WHERE conrelid = 'entrys'::regclass AND contype = 'u'
+    LOOP
+      EXECUTE format('ALTER TABLE entrys DROP CONSTRAINT %I', old_key);
+    END LOOP;
+    ALTER TABLE entrys ADD CONSTRAINT entrys_unique
+      UNIQUE (timestamp, micros, source, line_number, request_id, user_id);
+  END IF;
+END $$;
+CREATE INDEX IF NOT EXISTS entrys_user ON entrys(user_id, timestamp);
+
+-- Databases of earlier versions have the users unique by hash, useragent and
+-- country, or only indexed. The users of the same hash are merged to the
+-- first one, keeping one of the entries they have in common.
+DO $$
+BEGIN
+  IF to_regclass('users_hash') IS NULL THEN
+    ALTER TABLE users DROP CONSTRAINT IF EXISTS users_unique;
+    DROP INDEX IF EXISTS users_cols;
+    CREATE TEMPORARY TABLE users_merged ON COMMIT DROP AS
+      SELECT id, MIN(id) OVER (PARTITION BY hash) AS keep_id FROM users WHERE hash IS NOT NULL;
+    DELETE FROM users_merged WHERE id = keep_id;
+    DELETE FROM entrys e USING users_merged m
+      WHERE e.user_id = m.id AND EXISTS (
+        SELECT 1 FROM entrys k LEFT JOIN users_merged km ON k.user_id = km.id
+        WHERE COALESCE(km.keep_id, k.user_id) = m.keep_id
+          AND (k.user_id = m.keep_id OR k.id < e.id)
+          AND k.timestamp = e.timestamp AND k.micros = e.micros AND k.source = e.source
+          AND k.line_number = e.line_number AND k.request_id = e.request_id
+      );
+    UPDATE entrys e SET user_id = m.keep_id FROM users_merged m WHERE e.user_id = m.id;
+    UPDATE users k SET
+        country_id = COALESCE(k.country_id, merged.country_id),
+        is_bot = k.is_bot OR merged.is_bot
+      FROM (
+        SELECT m.keep_id, MIN(u.country_id) AS country_id, bool_or(u.is_bot) AS is_bot
+        FROM users_merged m JOIN users u ON u.id = m.id
+        GROUP BY m.keep_id
+      ) merged
+      WHERE k.id = merged.keep_id;
+    DELETE FROM users u USING users_merged m WHERE u.id = m.id;
+    ALTER TABLE users ADD CONSTRAINT users_hash UNIQUE (hash);
+  END IF;
+END $$;
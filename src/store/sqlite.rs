@@ -0,0 +1,60 @@
+use super::Store;
+use crate::db::{self, init, BatchCache, Result};
+use crate::importer::{Dedup, Msg};
+use crate::models::{LogEntry, User};
+use crate::referrers::mark_internal_domains;
+use crate::sessions::update_sessions;
+use crossbeam_channel::Sender;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+/// SQLite database file, see `schema.sql`. Each batch is inserted in one
+/// transaction together with its rollups and sketches.
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
+    cache: BatchCache,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it and the schema if needed
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(SqliteStore {
+            pool: init(path)?,
+            cache: BatchCache::new(),
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    fn populate(&mut self, msg_sender: &Sender<Msg>) -> Result<()> {
+        self.cache.populate(&self.pool.get().unwrap(), msg_sender)
+    }
+
+    fn insert_batch(
+        &mut self,
+        msg_sender: &Sender<Msg>,
+        entries: &[LogEntry],
+        dedup: Dedup,
+    ) -> Result<()> {
+        let mut conn = self.pool.get().unwrap();
+        let tx = conn.transaction()?;
+        db::batch_insert(msg_sender, &tx, entries, &mut self.cache, dedup)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn mark_bots(&mut self, users: &[User]) -> Result<()> {
+        db::mark_bots(&self.pool.get().unwrap(), users, &self.cache)
+    }
+
+    fn finish(&mut self, own_hosts: &[String], session_gap: i64) -> Result<usize> {
+        let mut conn = self.pool.get().unwrap();
+        let tx = conn.transaction()?;
+        if !own_hosts.is_empty() {
+            mark_internal_domains(&tx, own_hosts)?;
+        }
+        let sessions = update_sessions(&tx, session_gap)?;
+        tx.commit()?;
+        Ok(sessions)
+    }
+}
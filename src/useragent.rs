@@ -0,0 +1,249 @@
+//! User-agent classification with the uap-core regex database
+//!
+//! `uap_regexes.yaml` is bundled from https://github.com/ua-parser/uap-core
+//! (Apache License 2.0). Classification is done once per distinct useragent,
+//! so the linear scan over the parsers is not a bottleneck.
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex, RegexBuilder};
+use serde::Deserialize;
+
+const UAP_REGEXES: &str = include_str!("uap_regexes.yaml");
+
+static PARSERS: Lazy<Parsers> = Lazy::new(|| Parsers::from_yaml(UAP_REGEXES).unwrap());
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct UseragentDetails {
+    pub browser_family: String,
+    pub browser_major: Option<String>,
+    pub os_family: String,
+    pub device_type: DeviceType,
+    pub is_bot: bool,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    Bot,
+    Other,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::Bot => "bot",
+            DeviceType::Other => "other",
+        }
+    }
+}
+
+/// Classifies the useragent string
+pub fn classify(useragent: &str) -> UseragentDetails {
+    PARSERS.classify(useragent)
+}
+
+#[derive(Deserialize)]
+struct RegexFile {
+    user_agent_parsers: Vec<UserAgentParserDef>,
+    os_parsers: Vec<OsParserDef>,
+    device_parsers: Vec<DeviceParserDef>,
+}
+
+#[derive(Deserialize)]
+struct UserAgentParserDef {
+    regex: String,
+    family_replacement: Option<String>,
+    v1_replacement: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OsParserDef {
+    regex: String,
+    os_replacement: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeviceParserDef {
+    regex: String,
+    regex_flag: Option<String>,
+    device_replacement: Option<String>,
+}
+
+struct Matcher {
+    regex: Regex,
+    family_replacement: Option<String>,
+    v1_replacement: Option<String>,
+}
+
+impl Matcher {
+    fn new(
+        regex: &str,
+        case_insensitive: bool,
+        family_replacement: Option<String>,
+        v1_replacement: Option<String>,
+    ) -> Result<Self, regex::Error> {
+        Ok(Matcher {
+            regex: RegexBuilder::new(regex)
+                .case_insensitive(case_insensitive)
+                .size_limit(1 << 24)
+                .build()?,
+            family_replacement,
+            v1_replacement,
+        })
+    }
+
+    /// Returns the family and the first version part
+    fn apply(&self, value: &str) -> Option<(String, Option<String>)> {
+        let captures = self.regex.captures(value)?;
+        let family = replace(&captures, self.family_replacement.as_deref(), 1)?;
+        let v1 = replace(&captures, self.v1_replacement.as_deref(), 2);
+        Some((family, v1))
+    }
+}
+
+/// The uap-core replacement rules: replacement string with `$N` substituted,
+/// or the capture group `group` if there is no replacement
+fn replace(captures: &Captures, replacement: Option<&str>, group: usize) -> Option<String> {
+    let value = match replacement {
+        Some(replacement) => {
+            let mut value = replacement.to_owned();
+            for i in 1..captures.len() {
+                let needle = format!("${}", i);
+                if value.contains(&needle) {
+                    let capture = captures.get(i).map_or("", |m| m.as_str());
+                    value = value.replace(&needle, capture);
+                }
+            }
+            value
+        }
+        None => captures.get(group)?.as_str().to_owned(),
+    };
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_owned())
+}
+
+struct Parsers {
+    user_agent: Vec<Matcher>,
+    os: Vec<Matcher>,
+    device: Vec<Matcher>,
+}
+
+impl Parsers {
+    fn from_yaml(yaml: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: RegexFile = serde_yaml::from_str(yaml)?;
+        Ok(Parsers {
+            user_agent: file
+                .user_agent_parsers
+                .into_iter()
+                .map(|p| Matcher::new(&p.regex, false, p.family_replacement, p.v1_replacement))
+                .collect::<Result<_, _>>()?,
+            os: file
+                .os_parsers
+                .into_iter()
+                .map(|p| Matcher::new(&p.regex, false, p.os_replacement, None))
+                .collect::<Result<_, _>>()?,
+            device: file
+                .device_parsers
+                .into_iter()
+                .map(|p| {
+                    let case_insensitive = p.regex_flag.as_deref() == Some("i");
+                    Matcher::new(&p.regex, case_insensitive, p.device_replacement, None)
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn classify(&self, useragent: &str) -> UseragentDetails {
+        let (browser_family, browser_major) =
+            first_match(&self.user_agent, useragent).unwrap_or_else(|| ("Other".to_owned(), None));
+        let os_family = first_match(&self.os, useragent)
+            .map(|(family, _)| family)
+            .unwrap_or_else(|| "Other".to_owned());
+        let device_family = first_match(&self.device, useragent)
+            .map(|(family, _)| family)
+            .unwrap_or_else(|| "Other".to_owned());
+
+        let is_bot = device_family == "Spider" || browser_family == "Spider";
+        let device_type = device_type(is_bot, &device_family, &os_family);
+        UseragentDetails {
+            browser_family,
+            browser_major,
+            os_family,
+            device_type,
+            is_bot,
+        }
+    }
+}
+
+fn first_match(matchers: &[Matcher], value: &str) -> Option<(String, Option<String>)> {
+    matchers.iter().find_map(|m| m.apply(value))
+}
+
+/// uap-core has no device class, so it's guessed from the device and OS
+fn device_type(is_bot: bool, device_family: &str, os_family: &str) -> DeviceType {
+    if is_bot {
+        DeviceType::Bot
+    } else if device_family.contains("Tablet") || device_family.starts_with("iPad") {
+        DeviceType::Tablet
+    } else if device_family != "Other"
+        || matches!(os_family, "Android" | "iOS" | "Windows Phone" | "KaiOS")
+    {
+        // Desktops don't usually get a device family, except for Mac
+        if device_family == "Mac" {
+            DeviceType::Desktop
+        } else {
+            DeviceType::Mobile
+        }
+    } else if matches!(
+        os_family,
+        "Windows" | "Mac OS X" | "Linux" | "Ubuntu" | "Fedora" | "Chrome OS" | "FreeBSD"
+    ) {
+        DeviceType::Desktop
+    } else {
+        DeviceType::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, DeviceType};
+
+    #[test]
+    fn test_classify_desktop() {
+        let details = classify("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/96.0.4664.110 Safari/537.36");
+        assert_eq!("Chrome", details.browser_family);
+        assert_eq!(Some("96".to_owned()), details.browser_major);
+        assert_eq!("Windows", details.os_family);
+        assert_eq!(DeviceType::Desktop, details.device_type);
+        assert!(!details.is_bot);
+    }
+
+    #[test]
+    fn test_classify_mobile() {
+        let details = classify("Mozilla/5.0 (iPhone; CPU iPhone OS 15_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.1 Mobile/15E148 Safari/604.1");
+        assert_eq!("Mobile Safari", details.browser_family);
+        assert_eq!("iOS", details.os_family);
+        assert_eq!(DeviceType::Mobile, details.device_type);
+    }
+
+    #[test]
+    fn test_classify_bot() {
+        let details =
+            classify("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!(DeviceType::Bot, details.device_type);
+        assert!(details.is_bot);
+    }
+
+    #[test]
+    fn test_classify_unknown() {
+        let details = classify("foo");
+        assert_eq!("Other", details.browser_family);
+        assert_eq!(None, details.browser_major);
+        assert_eq!(DeviceType::Other, details.device_type);
+    }
+}
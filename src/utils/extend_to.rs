@@ -26,7 +26,7 @@ mod tests {
 
     #[test]
     fn extend_to_vec() {
-        let input = vec![4, 5];
+        let input = [4, 5];
         let mut receiver = vec![1, 2, 3];
         input.iter().extend_to(&mut receiver);
         assert_eq!(vec![1, 2, 3, 4, 5], receiver);
@@ -29,34 +29,14 @@ where
     type Item = Result<V, E2>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.iter.next() {
-                Some(Ok(v)) => return Some(Ok(v)),
-                Some(Err(v)) => return Some(Err((self.map_op)(v))),
-                None => return None,
-            }
+        match self.iter.next() {
+            Some(Ok(v)) => Some(Ok(v)),
+            Some(Err(v)) => Some(Err((self.map_op)(v))),
+            None => None,
         }
     }
 }
 
-pub trait MapErrsExt<I, V, E, E2, F>
-where
-    I: Iterator<Item = Result<V, E>>,
-    F: Fn(E) -> E2 + Sync + Send,
-{
-    fn map_errs(self, f: F) -> MapErrs<I, F>;
-}
-
-impl<I, V, E, E2, F> MapErrsExt<I, V, E, E2, F> for I
-where
-    I: Iterator<Item = Result<V, E>>,
-    F: Fn(E) -> E2 + Sync + Send,
-{
-    fn map_errs(self, f: F) -> MapErrs<I, F> {
-        MapErrs::new(self, f)
-    }
-}
-
 /* ----------------------------------------------------------------------- */
 
 // TODO: Simplyfy the generics, they are overqualifying
@@ -99,21 +79,3 @@ where
             .drive_unindexed(consumer)
     }
 }
-
-pub trait ParallelMapErrsExt<I, V, E, E2, F>
-where
-    I: ParallelIterator<Item = Result<V, E>>,
-    F: Fn(E) -> E2 + Sync + Send,
-{
-    fn map_errs(self, f: F) -> ParallelMapErrs<I, F>;
-}
-
-impl<I, V, E, E2, F> ParallelMapErrsExt<I, V, E, E2, F> for I
-where
-    I: ParallelIterator<Item = Result<V, E>>,
-    F: Fn(E) -> E2 + Sync + Send,
-{
-    fn map_errs(self, f: F) -> ParallelMapErrs<I, F> {
-        ParallelMapErrs::new(self, f)
-    }
-}
@@ -1,8 +1,6 @@
 mod extend_to;
 mod map_errs;
-mod run_after_timeout;
 mod send_errors;
 pub use extend_to::*;
 pub use map_errs::*;
-pub use run_after_timeout::*;
 pub use send_errors::*;
@@ -16,7 +16,7 @@ where
     I: Iterator<Item = Result<T, E>>,
     M: From<E>,
 {
-    pub(self) fn new(iter: I, channel: &'s crossbeam_channel::Sender<M>) -> SendErrors<I, M> {
+    pub(self) fn new(iter: I, channel: &'s crossbeam_channel::Sender<M>) -> SendErrors<'s, I, M> {
         SendErrors { iter, channel }
     }
 }
@@ -50,7 +50,7 @@ where
     M: From<E>,
 {
     /// Transmit errors to a channel, leaving Ok values in the iterator
-    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> SendErrors<T, M>;
+    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> SendErrors<'_, T, M>;
 }
 
 impl<T, V, E, M> SendErrorsExt<T, V, E, M> for T
@@ -59,7 +59,7 @@ where
     M: From<E>,
 {
     /// Transmit errors to a channel, leaving Ok values in the iterator
-    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> SendErrors<T, M> {
+    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> SendErrors<'_, T, M> {
         SendErrors::new(self, channel)
     }
 }
@@ -78,7 +78,7 @@ where
         self,
         channel: &crossbeam_channel::Sender<M>,
         map_op: F,
-    ) -> SendErrors<MapErrs<T, F>, M>;
+    ) -> SendErrors<'_, MapErrs<T, F>, M>;
 }
 
 impl<T, V, E, M, E2, F> SendErrorsAsExt<T, V, E, M, E2, F> for T
@@ -94,7 +94,7 @@ where
         self,
         channel: &crossbeam_channel::Sender<M>,
         map_op: F,
-    ) -> SendErrors<MapErrs<T, F>, M> {
+    ) -> SendErrors<'_, MapErrs<T, F>, M> {
         SendErrors::new(MapErrs::new(self, map_op), channel)
     }
 }
@@ -151,27 +151,6 @@ where
     }
 }
 
-// Adds the `transmit_error` to the ParallelIterator
-pub trait ParallelSendErrorsExt<T, V, E, M>
-where
-    T: ParallelIterator<Item = Result<V, E>>,
-    M: From<E>,
-{
-    /// Transmit errors to a channel, leaving Ok values in the iterator
-    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> ParallelSendErrors<T, E, V, M>;
-}
-
-impl<T, V, E, M> ParallelSendErrorsExt<T, V, E, M> for T
-where
-    T: ParallelIterator<Item = Result<V, E>>,
-    M: From<E>,
-{
-    /// Transmit errors to a channel, leaving Ok values in the iterator
-    fn send_errors(self, channel: &crossbeam_channel::Sender<M>) -> ParallelSendErrors<T, E, V, M> {
-        ParallelSendErrors::new(self, channel.clone())
-    }
-}
-
 // Adds the `transmit_error` to the Iterator
 pub trait ParallelSendErrorsAsExt<T, V, E, M, E2, F>
 where